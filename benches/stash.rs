@@ -0,0 +1,49 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use hashmem::TokenStash;
+
+const ORDER: usize = 8;
+
+/// A fixed, repetitive but not periodic corpus of about 10 KB.
+fn corpus() -> String {
+    let words = [
+        "the", "quick", "brown", "fox", "jumps", "over", "lazy", "dog", "and", "runs",
+    ];
+    let mut out = String::new();
+    for i in 0..2000 {
+        out.push_str(words[(i * 7 + i / 3) % words.len()]);
+        out.push(if i % 17 == 16 { '\n' } else { ' ' });
+    }
+    out
+}
+
+fn note_text(c: &mut Criterion) {
+    let text = corpus();
+    c.bench_function("note_text", |b| {
+        b.iter_batched(
+            || {
+                let dir = tempfile::tempdir().unwrap();
+                let stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+                (dir, stash)
+            },
+            |(_dir, mut stash)| stash.note_text(black_box(&text), ORDER).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+fn predict_token(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+    stash.note_text(&corpus(), ORDER).unwrap();
+    let queries = ["t", "the qu", "fox ju", "lazy d", "runs th", "zzz"];
+    c.bench_function("predict_token", |b| {
+        b.iter(|| {
+            for q in queries {
+                black_box(stash.predict_token(black_box(q)).unwrap());
+            }
+        })
+    });
+}
+
+criterion_group!(benches, note_text, predict_token);
+criterion_main!(benches);
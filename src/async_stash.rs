@@ -0,0 +1,92 @@
+use std::sync::{Arc, Mutex};
+
+use crate::error::Result;
+use crate::stash::TokenStash;
+use crate::token::TokenEntry;
+
+/// A [`TokenStash`] usable from async code without blocking the runtime.
+///
+/// The stash is moved behind a shared lock, and every call runs on tokio's
+/// blocking thread pool through `spawn_blocking`, so it must be made from
+/// within a tokio runtime. Calls are executed one at a time. Cloning is cheap
+/// and shares the same stash.
+#[derive(Clone)]
+pub struct AsyncTokenStash {
+    inner: Arc<Mutex<TokenStash>>,
+}
+
+impl AsyncTokenStash {
+    /// Take ownership of `stash`.
+    pub fn new(stash: TokenStash) -> Self {
+        AsyncTokenStash {
+            inner: Arc::new(Mutex::new(stash)),
+        }
+    }
+
+    /// Run `f` with the stash on the blocking pool and return its result.
+    ///
+    /// A panic in `f` is resumed in the caller.
+    pub async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut TokenStash) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = self.inner.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let mut stash = inner.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut stash)
+        });
+        match task.await {
+            Ok(result) => result,
+            Err(e) => match e.try_into_panic() {
+                Ok(panic) => std::panic::resume_unwind(panic),
+                Err(e) => Err(std::io::Error::from(e).into()),
+            },
+        }
+    }
+
+    /// See [`TokenStash::note_text`].
+    pub async fn note_text(&self, text: String, context: usize) -> Result<()> {
+        self.run(move |stash| stash.note_text(&text, context)).await
+    }
+
+    /// See [`TokenStash::predict_token`].
+    pub async fn predict_token(&self, input: String) -> Result<Vec<TokenEntry>> {
+        self.run(move |stash| stash.predict_token(&input)).await
+    }
+
+    /// See [`TokenStash::generate_string`].
+    pub async fn generate_string(
+        &self,
+        input: String,
+        context: usize,
+        max_len: usize,
+    ) -> Result<String> {
+        self.run(move |stash| stash.generate_string(&input, context, max_len))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::Token;
+
+    #[test]
+    fn calls_run_on_the_blocking_pool() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let stash = AsyncTokenStash::new(TokenStash::in_memory());
+        runtime.block_on(async {
+            stash.note_text("abc".to_string(), 2).await.unwrap();
+            let after_a = stash.predict_token("a".to_string()).await.unwrap();
+            assert_eq!(after_a[0].value, Token::C('b'));
+            let text = stash.generate_string("a".to_string(), 2, 10).await.unwrap();
+            assert_eq!(text, "bc");
+            let shared = stash.clone();
+            let trained = shared.run(|s| s.is_trained()).await.unwrap();
+            assert!(trained);
+        });
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt;
+
+/// Everything that can go wrong while reading or writing a model.
+#[derive(Debug)]
+pub enum HashmemError {
+    /// The underlying redb database failed.
+    Db(Box<redb::Error>),
+    /// A value could not be serialized.
+    Encode(bincode::error::EncodeError),
+    /// A stored value could not be deserialized.
+    Decode(bincode::error::DecodeError),
+    /// A filesystem operation failed.
+    Io(std::io::Error),
+    /// JSON could not be read or written.
+    Json(serde_json::Error),
+    /// The database file is damaged and could not be repaired on open.
+    ///
+    /// Opening it with [`StoreOptions::repair`](crate::StoreOptions::repair)
+    /// may recover it; otherwise it has to be deleted and the model retrained.
+    Corrupted(String),
+    /// The database was written before contexts were stored with their hits.
+    ///
+    /// Its counts cannot be told apart by context, so it has to be deleted
+    /// and the model retrained.
+    RetrainRequired(String),
+}
+
+pub type Result<T> = std::result::Result<T, HashmemError>;
+
+impl fmt::Display for HashmemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashmemError::Db(e) => write!(f, "database error: {}", e),
+            HashmemError::Encode(e) => write!(f, "encode error: {}", e),
+            HashmemError::Decode(e) => write!(f, "decode error: {}", e),
+            HashmemError::Io(e) => write!(f, "io error: {}", e),
+            HashmemError::Json(e) => write!(f, "json error: {}", e),
+            HashmemError::Corrupted(e) => write!(f, "corrupted database: {}", e),
+            HashmemError::RetrainRequired(e) => write!(f, "database must be retrained: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HashmemError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HashmemError::Db(e) => Some(e.as_ref()),
+            HashmemError::Encode(e) => Some(e),
+            HashmemError::Decode(e) => Some(e),
+            HashmemError::Io(e) => Some(e),
+            HashmemError::Json(e) => Some(e),
+            HashmemError::Corrupted(_) | HashmemError::RetrainRequired(_) => None,
+        }
+    }
+}
+
+macro_rules! from_db_error {
+    ($($t:ty),*) => {
+        $(impl From<$t> for HashmemError {
+            fn from(e: $t) -> Self {
+                match e.into() {
+                    redb::Error::Corrupted(msg) => HashmemError::Corrupted(msg),
+                    // redb reports a file that is not a valid database this way.
+                    redb::Error::Io(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                        HashmemError::Corrupted(e.to_string())
+                    }
+                    e => HashmemError::Db(Box::new(e)),
+                }
+            }
+        })*
+    };
+}
+
+from_db_error!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+impl From<bincode::error::EncodeError> for HashmemError {
+    fn from(e: bincode::error::EncodeError) -> Self {
+        HashmemError::Encode(e)
+    }
+}
+
+impl From<bincode::error::DecodeError> for HashmemError {
+    fn from(e: bincode::error::DecodeError) -> Self {
+        HashmemError::Decode(e)
+    }
+}
+
+impl From<std::io::Error> for HashmemError {
+    fn from(e: std::io::Error) -> Self {
+        HashmemError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for HashmemError {
+    fn from(e: serde_json::Error) -> Self {
+        HashmemError::Json(e)
+    }
+}
@@ -0,0 +1,180 @@
+use sha256::digest;
+
+use crate::token::Token;
+
+/// Contexts encoding to at most this many bytes skip hashing under the digest strategies.
+///
+/// That covers single characters and most two- and three-character contexts,
+/// which back-off looks up most often.
+const SHORT_CONTEXT_BYTES: usize = 8;
+
+/// How a context (a token sequence) is turned into a database key.
+///
+/// The strategy is not recorded in the database, so a model must always be
+/// opened with the strategy it was trained with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyStrategy {
+    /// Hex SHA-256 of the bincode-encoded context (64 characters).
+    ///
+    /// Short contexts are stored like [`RawBincode`](Self::RawBincode) instead,
+    /// which is cheaper to compute and cannot collide with a digest: hex digits
+    /// never include the `r` prefix.
+    #[default]
+    Sha256,
+    /// Hex BLAKE3 of the bincode-encoded context (64 characters), faster to compute.
+    ///
+    /// Short contexts skip hashing as with [`Sha256`](Self::Sha256).
+    Blake3,
+    /// The bincode-encoded context itself, hex encoded behind an `r` prefix.
+    ///
+    /// Collision-free and much shorter for short contexts (7 characters for a
+    /// single ASCII character), but grows with the context length.
+    RawBincode,
+    /// Maps every context to the same key, to exercise collision handling.
+    #[cfg(test)]
+    Constant,
+}
+
+fn encode_tokens(tokens: &[Token]) -> Vec<u8> {
+    bincode::encode_to_vec(tokens, bincode::config::standard()).unwrap()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn raw_key(encoded: &[u8]) -> String {
+    format!("r{}", to_hex(encoded))
+}
+
+/// The key the digest strategies use for `tokens` instead of a digest, if they are short enough.
+pub(crate) fn short_key(tokens: &[Token]) -> Option<String> {
+    let encoded = encode_tokens(tokens);
+    (encoded.len() <= SHORT_CONTEXT_BYTES).then(|| raw_key(&encoded))
+}
+
+impl KeyStrategy {
+    /// The database key for `tokens` under this strategy.
+    pub fn key(&self, tokens: &[Token]) -> String {
+        let encoded = encode_tokens(tokens);
+        match self {
+            KeyStrategy::Sha256 | KeyStrategy::Blake3 if encoded.len() <= SHORT_CONTEXT_BYTES => {
+                raw_key(&encoded)
+            }
+            KeyStrategy::Sha256 => digest(&encoded[..]),
+            KeyStrategy::Blake3 => blake3::hash(&encoded).to_hex().to_string(),
+            KeyStrategy::RawBincode => raw_key(&encoded),
+            #[cfg(test)]
+            KeyStrategy::Constant => "collide".to_string(),
+        }
+    }
+
+    /// The full database key for the context `tokens`: its length, a colon and
+    /// its [`key`](Self::key), exactly as stored by a stash using this strategy.
+    pub fn context_key(&self, tokens: &[Token]) -> String {
+        format!("{}:{}", tokens.len(), self.key(tokens))
+    }
+
+    /// The context behind a raw key, given with or without its length prefix.
+    ///
+    /// Only raw keys (those of [`RawBincode`](Self::RawBincode) and the short
+    /// contexts of the digest strategies) can be turned back into a context;
+    /// digests and malformed keys give `None`. The context of any key is also
+    /// stored in its value, see [`TokenStash::contexts`](crate::TokenStash::contexts).
+    pub fn raw_context(key: &str) -> Option<Vec<Token>> {
+        let (len, raw) = match key.split_once(':') {
+            Some((len, raw)) => (Some(len.parse::<usize>().ok()?), raw),
+            None => (None, key),
+        };
+        let hex = raw.strip_prefix('r')?;
+        if hex.len() % 2 != 0 {
+            return None;
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let (tokens, read): (Vec<Token>, usize) =
+            bincode::decode_from_slice(&bytes, bincode::config::standard()).ok()?;
+        (read == bytes.len() && len.is_none_or(|len| len == tokens.len())).then_some(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_keys_are_short_and_distinct_from_digests() {
+        let context = [Token::C('a')];
+        let raw = KeyStrategy::RawBincode.key(&context);
+        assert_eq!(raw.len(), 7);
+        assert!(raw.starts_with('r'));
+        let long = [Token::Word("a longer context".to_string())];
+        assert_eq!(KeyStrategy::Sha256.key(&long).len(), 64);
+        assert_eq!(KeyStrategy::Blake3.key(&long).len(), 64);
+        assert_ne!(
+            KeyStrategy::Sha256.key(&long),
+            KeyStrategy::Blake3.key(&long)
+        );
+    }
+
+    #[test]
+    fn short_contexts_skip_hashing() {
+        let context = [Token::C('a')];
+        assert_eq!(
+            KeyStrategy::Sha256.key(&context),
+            KeyStrategy::RawBincode.key(&context)
+        );
+        assert_eq!(
+            KeyStrategy::Blake3.key(&context),
+            KeyStrategy::RawBincode.key(&context)
+        );
+        assert_eq!(
+            short_key(&context),
+            Some(KeyStrategy::RawBincode.key(&context))
+        );
+        let long: Vec<Token> = "abcdefghij".chars().map(Token::C).collect();
+        assert!(short_key(&long).is_none());
+        assert!(!KeyStrategy::Sha256.key(&long).starts_with('r'));
+    }
+
+    #[test]
+    fn keys_are_stable_across_releases() {
+        let short = [Token::C('a')];
+        let long: Vec<Token> = "hello world".chars().map(Token::C).collect();
+        assert_eq!(KeyStrategy::Sha256.context_key(&short), "1:r010061");
+        assert_eq!(KeyStrategy::RawBincode.context_key(&[]), "0:r00");
+        assert_eq!(
+            KeyStrategy::Sha256.context_key(&long),
+            "11:478b27b2cfd823a1149df87d09dd2fb7b3596e7307b5468a29eae78a7931725a"
+        );
+        assert_eq!(
+            KeyStrategy::Blake3.context_key(&long),
+            "11:5b2e993c1573e0113b8ca57f45ee028b3c7a712bde67c84566ff9a479ac5cfa3"
+        );
+    }
+
+    #[test]
+    fn raw_keys_round_trip_to_their_context() {
+        let contexts = [
+            vec![],
+            vec![Token::C('a')],
+            vec![Token::Bos, Token::Num(42), Token::Word("wörd".to_string())],
+        ];
+        for context in contexts {
+            let key = KeyStrategy::RawBincode.context_key(&context);
+            assert_eq!(KeyStrategy::raw_context(&key), Some(context.clone()));
+            let bare = KeyStrategy::RawBincode.key(&context);
+            assert_eq!(KeyStrategy::raw_context(&bare), Some(context));
+        }
+        let long: Vec<Token> = "hello world".chars().map(Token::C).collect();
+        assert_eq!(
+            KeyStrategy::raw_context(&KeyStrategy::Sha256.context_key(&long)),
+            None
+        );
+        assert_eq!(KeyStrategy::raw_context("2:r010061"), None);
+        assert_eq!(KeyStrategy::raw_context("r0100"), None);
+        assert_eq!(KeyStrategy::raw_context("rzz"), None);
+    }
+}
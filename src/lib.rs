@@ -0,0 +1,43 @@
+//! A character-level n-gram language model stored in redb.
+//!
+//! ```no_run
+//! use hashmem::TokenStash;
+//!
+//! # fn main() -> hashmem::Result<()> {
+//! let mut stash = TokenStash::new("data")?;
+//! stash.note_text("the quick brown fox", 32)?;
+//! let candidates = stash.predict_token("th")?;
+//! # Ok(())
+//! # }
+//! ```
+
+#[macro_use]
+extern crate log;
+
+#[cfg(feature = "async")]
+mod async_stash;
+mod error;
+mod key;
+mod preprocess;
+mod stash;
+mod store;
+mod token;
+mod tokenizer;
+
+#[cfg(feature = "async")]
+pub use async_stash::AsyncTokenStash;
+pub use error::{HashmemError, Result};
+pub use key::KeyStrategy;
+pub use preprocess::{collapse_whitespace, strip_control_chars, Preprocessor};
+pub use stash::{
+    Fallback, FlushPolicy, GenState, ImportMode, ModelStats, OrderSize, PredictTrace, StopReason,
+    TieBreak, TokenStash, DEFAULT_BACKOFF_DISCOUNT, DEFAULT_CACHE_CAPACITY, DEFAULT_LOOP_LIMIT,
+    DEFAULT_ORDER, DEFAULT_REPEAT_WINDOW, DEFAULT_SENTENCE_ENDS, DEFAULT_SMOOTHING_FLOOR,
+    PROGRESS_INTERVAL,
+};
+pub use store::{
+    BloomStore, MemoryStore, NamespacedStore, RedbStore, SharedStore, Store, StoreIter,
+    StoreOptions,
+};
+pub use token::{EosMode, Normalization, Token, TokenEntry, TokenHits, TokenMode};
+pub use tokenizer::{ByteTokenizer, CharTokenizer, Tokenizer, WordTokenizer};
@@ -1,46 +1,15 @@
-use bincode::{Decode, Encode};
-use rand;
-use rand::Rng;
-use std::fs::File;
-
-use serde::{Deserialize, Serialize};
-use sha256::{digest, try_digest};
-use std::collections::HashMap;
-use std::io::{Read, Write};
+use hashmem::{
+    HashmemError, StoreOptions, Token, TokenEntry, TokenMode, TokenStash, DEFAULT_ORDER,
+};
 
 #[macro_use]
 extern crate log;
 
-use redb::{Database, ReadableTable, TableDefinition};
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Encode, Decode)]
-enum Token {
-    C(char),
-    Num(u64),
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Encode, Decode)]
-struct TokenEntry {
-    value: Token,
-    count: u64,
-}
+mod repl;
+mod serve;
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize, Encode, Decode)]
-struct TokenHits {
-    entries: Vec<TokenEntry>,
-}
-
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-struct TokenHitHash {
-    hits_by_hash: HashMap<String, TokenHits>,
-}
-
-struct TokenStash {
-    prefix: String,
-    database: Database,
-    cache: HashMap<String, TokenHitHash>,
-    rng: rand::ThreadRng,
-}
+use redb::{Database, TableDefinition};
+use std::io::Read;
 
 fn test_db() {
     const TABLE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("test_table");
@@ -58,7 +27,7 @@ fn test_db() {
     }
 
     let db = Database::create(path).unwrap();
-    
+
     let write_txn = db.begin_write().unwrap();
     {
         let mut table = write_txn.open_table(TABLE).unwrap();
@@ -75,7 +44,8 @@ fn test_db() {
 
     match res {
         Some(data) => {
-            let (decoded, _): (TokenEntry, usize) = bincode::decode_from_slice(data.value(), bincode::config::standard()).unwrap();
+            let (decoded, _): (TokenEntry, usize) =
+                bincode::decode_from_slice(data.value(), bincode::config::standard()).unwrap();
             println!("Data retrieved: {:?}", &decoded);
         }
         None => {
@@ -84,269 +54,342 @@ fn test_db() {
     }
 }
 
-impl TokenStash {
-    fn new(prefix: &str) -> Self {
-        let dbname = format!("{}/db", &prefix);
-        
-        // Create parent directory if it doesn't exist
-        if let Some(parent) = std::path::Path::new(&dbname).parent() {
-            std::fs::create_dir_all(parent).unwrap();
-        }
-        
-        let database = match Database::create(&dbname) {
-            Ok(db) => db,
-            Err(e) => {
-                panic!("failed to open database: {:?}", e)
-            }
-        };
+/// A readable, unambiguous form of `token` for listings.
+fn describe(token: &Token) -> String {
+    match token {
+        Token::C(c) => format!("{:?}", c),
+        Token::Num(n) => n.to_string(),
+        Token::Word(w) => format!("{:?}", w),
+        Token::Eos => "<eos>".to_string(),
+        Token::Bos => "<bos>".to_string(),
+        Token::Byte(b) => format!("0x{:02x}", b),
+    }
+}
 
-        TokenStash {
-            rng: rand::thread_rng(),
-            prefix: prefix.to_string(),
-            database,
-            cache: HashMap::new(),
+/// Train `stash` on the file `fname`, decompressing it if it ends in `.gz`.
+fn note_file(stash: &mut TokenStash, fname: &str, order: usize, dedup: bool) {
+    if dedup {
+        let mut data = String::new();
+        let file = std::fs::File::open(fname).unwrap();
+        if fname.ends_with(".gz") {
+            flate2::read::MultiGzDecoder::new(file).read_to_string(&mut data)
+        } else {
+            std::io::BufReader::new(file).read_to_string(&mut data)
+        }
+        .unwrap();
+        if !stash.note_text_once(&data, order).unwrap() {
+            eprintln!("{} was already trained, skipping", fname);
         }
+    } else if fname.ends_with(".gz") {
+        // Decompress while streaming, line by line.
+        let file = std::fs::File::open(fname).unwrap();
+        let reader = std::io::BufReader::new(flate2::read::MultiGzDecoder::new(file));
+        note_lines(stash, reader, order);
+    } else {
+        let data = std::fs::read_to_string(fname).unwrap();
+        stash
+            .note_text_with_progress(&data, order, |processed, total| {
+                eprint!(
+                    "\rProgress: {}/{} tokens noted ({}%)",
+                    processed,
+                    total,
+                    processed * 100 / total.max(1)
+                );
+            })
+            .unwrap();
+        eprintln!();
     }
+}
+
+/// Train `stash` on `reader` line by line, showing how many lines were noted.
+fn note_lines<R: std::io::BufRead>(stash: &mut TokenStash, reader: R, order: usize) {
+    stash
+        .note_reader_with_progress(reader, order, |lines| {
+            eprint!("\rProgress: {} lines noted", lines);
+        })
+        .unwrap();
+    eprintln!();
+}
 
-    fn tokenize(self: &Self, src: &str) -> Vec<Token> {
-        src.chars().map(|x| Token::C(x)).collect()
+const USAGE: &str = "\
+usage: hashmem [options] <command> [arguments]
+
+commands:
+  note <text>            train on text
+  note-file <file>...    train on files, .gz ones line by line
+  note-stdin             train on stdin, line by line
+  predict <text>         show the predictions after text
+  dump <context>         list what followed context, with counts
+  generate <seed>        continue seed with generated text
+  perplexity <file>      score the model on a held-out file
+  prune <min-count>      drop continuations seen fewer times
+  repl                   alternate training and generating
+  reset                  delete everything learned
+  serve                  answer completions over HTTP
+  stats                  summarize the model
+
+options:
+  --db PATH  --context N  --port N  --max-len N  --seed N
+  --dedup  --no-trailing-newline  --repair  -v/-vv/-vvv";
+
+/// How many arguments `command` needs after its name, or `None` if there is no such command.
+fn required_args(command: &str) -> Option<usize> {
+    match command {
+        "note" | "note-file" | "predict" | "dump" | "generate" | "perplexity" | "prune" => Some(1),
+        "note-stdin" | "repl" | "reset" | "serve" | "stats" | "test" => Some(0),
+        _ => None,
     }
+}
 
-    fn hash_tokens(self: &Self, src: &[Token]) -> String {
-        let encoded: Vec<u8> = bincode::encode_to_vec(&src, bincode::config::standard()).unwrap();
-        digest(&encoded[..])
+/// Check that `positional` names a command followed by the arguments it needs.
+fn check_usage(positional: &[String]) -> Result<(), String> {
+    let Some(command) = positional.first() else {
+        return Err("no command given".to_string());
+    };
+    match required_args(command) {
+        None => Err(format!("{} is not a valid operation", command)),
+        Some(n) if positional.len() <= n => Err(format!("{} needs an argument", command)),
+        Some(_) => Ok(()),
     }
+}
+
+/// Command line options shared by all subcommands, plus the remaining positional arguments.
+struct Args {
+    db: String,
+    order: usize,
+    port: u16,
+    dedup: bool,
+    max_len: usize,
+    trailing_newline: bool,
+    repair: bool,
+    verbose: usize,
+    seed: Option<u64>,
+    positional: Vec<String>,
+}
 
-    fn read_hits_from_file(self: &mut Self, hash: &str) -> TokenHits {
-        const TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("token_hits");
-        
-        let read_txn = self.database.begin_read().unwrap();
-        let table = read_txn.open_table(TABLE);
-        
-        match table {
-            Ok(table) => {
-                match table.get(hash).unwrap() {
-                    Some(data) => {
-                        let (decoded, _): (TokenHits, usize) = bincode::decode_from_slice(data.value(), bincode::config::standard()).unwrap();
-                        decoded
-                    }
-                    None => {
-                        TokenHits { entries: vec![] }
-                    }
-                }
+fn parse_args() -> Args {
+    let mut args = Args {
+        db: "data".to_string(),
+        order: DEFAULT_ORDER,
+        port: 8080,
+        dedup: false,
+        max_len: usize::MAX,
+        trailing_newline: true,
+        repair: false,
+        verbose: 0,
+        seed: None,
+        positional: vec![],
+    };
+    let mut it = std::env::args().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--db" => {
+                args.db = it.next().expect("--db needs a path");
+            }
+            "--context" => {
+                let n = it.next().expect("--context needs a value");
+                args.order = n.parse().expect("--context must be a number");
+            }
+            "--port" => {
+                let n = it.next().expect("--port needs a value");
+                args.port = n.parse().expect("--port must be a port number");
             }
-            Err(_) => {
-                // Table doesn't exist yet
-                TokenHits { entries: vec![] }
+            "--dedup" => args.dedup = true,
+            "--max-len" => {
+                let n = it.next().expect("--max-len needs a value");
+                args.max_len = n.parse().expect("--max-len must be a number");
             }
+            "--no-trailing-newline" => args.trailing_newline = false,
+            "--repair" => args.repair = true,
+            "--seed" => {
+                let n = it.next().expect("--seed needs a value");
+                args.seed = Some(n.parse().expect("--seed must be a number"));
+            }
+            "-v" | "--verbose" => args.verbose += 1,
+            // -vv, -vvv, ...
+            v if v.len() > 2 && v.starts_with('-') && v[1..].chars().all(|c| c == 'v') => {
+                args.verbose += v.len() - 1;
+            }
+            _ => args.positional.push(arg),
         }
     }
+    args
+}
 
-    fn write_hits_to_file(&mut self, hits: &TokenHits, hash: &str) {
-        const TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("token_hits");
-        
-        let write_txn = self.database.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(TABLE).unwrap();
-            let encoded: Vec<u8> = bincode::encode_to_vec(&hits, bincode::config::standard()).unwrap();
-            table.insert(hash, encoded.as_slice()).unwrap();
+fn main() {
+    let args = parse_args();
+    if let Err(e) = check_usage(&args.positional) {
+        eprintln!("{}\n\n{}", e, USAGE);
+        std::process::exit(2);
+    }
+    let mut logger = env_logger::Builder::from_default_env();
+    // Each -v selects a more verbose level, info, then debug, then trace,
+    // replacing the one from RUST_LOG.
+    match args.verbose {
+        0 => {}
+        1 => {
+            logger.filter_level(log::LevelFilter::Info);
+        }
+        2 => {
+            logger.filter_level(log::LevelFilter::Debug);
+        }
+        _ => {
+            logger.filter_level(log::LevelFilter::Trace);
         }
-        write_txn.commit().unwrap();
     }
+    logger.init();
+    debug!("this is a debug {}", "message");
 
-    fn note_next_token(&mut self, current: &[Token], next: &Token) {
-        let hash = self.hash_tokens(current);
-        let mut hits = self.read_hits_from_file(&hash);
-        debug!("current: {:?} next: {:?}, hash: {}", current, next, &hash);
-        debug!("Hits B4: {:?}", &hits);
-        let mut found = false;
-        for mut e in &mut hits.entries {
-            if &e.value == next {
-                e.count += 1;
-                found = true;
+    let order = args.order;
+    let options = StoreOptions {
+        repair: args.repair,
+        ..StoreOptions::default()
+    };
+    let mut stash = match TokenStash::with_options(&args.db, TokenMode::Char, &options) {
+        Ok(stash) => stash,
+        Err(HashmemError::Corrupted(e)) => {
+            eprintln!("The database in {} is corrupted: {}", args.db, e);
+            if args.repair {
+                eprintln!("It could not be repaired; delete it and train the model again.");
+            } else {
+                eprintln!("Run the command again with --repair to try to recover it.");
             }
+            std::process::exit(1);
         }
-        if !found {
-            let entry = TokenEntry {
-                value: next.clone(),
-                count: 1,
-            };
-            hits.entries.push(entry);
+        Err(e) => {
+            eprintln!("Could not open the database in {}: {}", args.db, e);
+            std::process::exit(1);
         }
-        debug!("Hits AF: {:?}", &hits);
-        self.write_hits_to_file(&hits, &hash);
-    }
-
-    fn get_next_candidates(&mut self, current: &[Token]) -> Vec<TokenEntry> {
-        let mut v: Vec<TokenEntry> = vec![];
-        let hash = self.hash_tokens(current);
-        debug!("input: {:?} hash: {}", &current, &hash);
-        let mut hits = self.read_hits_from_file(&hash);
-        debug!("hits: {:?}", &hits);
-        hits.entries
-    }
+    };
 
-    fn note_string(&mut self, input: &str) {
-        let input_tokenized = self.tokenize(&input);
-        debug!("Tokenized: {:?}", &input_tokenized);
-        self.note_next_token(
-            &input_tokenized[0..input_tokenized.len() - 1],
-            &input_tokenized[input_tokenized.len() - 1],
-        );
+    let command = args.positional[0].as_str();
+    if matches!(command, "predict" | "generate" | "dump" | "perplexity")
+        && !stash.is_trained().unwrap()
+    {
+        eprintln!("The model has no data yet; train it with note, note-file or note-stdin first.");
+        std::process::exit(1);
     }
 
-    fn note_all_string(&mut self, input: &str, context: usize) {
-        for i in 0..context {
-            if input.len() > 1 + i {
-                self.note_string(&input[input.len() - 2 - i..])
-            }
-        }
+    if command == "generate" {
+        // Pick a seed even when none was given, so a run can be reproduced.
+        let seed = args.seed.unwrap_or_else(|| {
+            let seed = rand::random();
+            eprintln!(
+                "Using seed {} (pass --seed {} to repeat this run)",
+                seed, seed
+            );
+            seed
+        });
+        stash.set_seed(seed);
+    } else if let Some(seed) = args.seed {
+        stash.set_seed(seed);
     }
 
-    fn note_text(&mut self, input: &str, context: usize) {
-        let total = input.len();
-        let mut batch: HashMap<String, TokenHits> = HashMap::new();
-        
-        // Collect all updates in memory
-        for i in 2..input.len() {
-            for j in 0..context {
-                if i > 1 + j {
-                    let end = i;
-                    let start = i - 2 - j;
-                    if start < end {
-                        let substring = &input[start..end];
-                        let tokenized = self.tokenize(substring);
-                        if tokenized.len() >= 1 {
-                            let current = &tokenized[0..tokenized.len() - 1];
-                            let next = &tokenized[tokenized.len() - 1];
-                            let hash = self.hash_tokens(current);
-                            
-                            let hits = batch.entry(hash.clone()).or_insert_with(|| {
-                                self.read_hits_from_file(&hash)
-                            });
-                            
-                            let mut found = false;
-                            for e in &mut hits.entries {
-                                if &e.value == next {
-                                    e.count += 1;
-                                    found = true;
-                                    break;
-                                }
-                            }
-                            if !found {
-                                let entry = TokenEntry {
-                                    value: next.clone(),
-                                    count: 1,
-                                };
-                                hits.entries.push(entry);
-                            }
-                        }
-                    }
-                }
-            }
-            if i % 100 == 0 {
-                eprint!("\rProgress: {}/{} characters noted ({}%)", i, total, (i * 100) / total);
-            }
+    match command {
+        "note" => {
+            stash.note_text(&args.positional[1], order).unwrap();
         }
-        
-        // Write all updates in a single transaction
-        let write_txn = self.database.begin_write().unwrap();
-        {
-            const TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("token_hits");
-            let mut table = write_txn.open_table(TABLE).unwrap();
-            
-            for (hash, hits) in &batch {
-                let encoded: Vec<u8> = bincode::encode_to_vec(&hits, bincode::config::standard()).unwrap();
-                table.insert(hash.as_str(), encoded.as_slice()).unwrap();
+        "note-file" => {
+            let files = &args.positional[1..];
+            for (i, fname) in files.iter().enumerate() {
+                eprintln!("[{}/{}] Noting {}...", i + 1, files.len(), fname);
+                note_file(&mut stash, fname, order, args.dedup);
             }
         }
-        write_txn.commit().unwrap();
-        
-        eprintln!(); // New line after progress completes
-    }
-
-    fn predict_token(&mut self, input: &str) -> Vec<TokenEntry> {
-        let input_tokenized = self.tokenize(&input);
-        let cand = self.get_next_candidates(&input_tokenized);
-        debug!("Candidates for {:?} : {:?}", &input_tokenized, &cand);
-        let mut v: Vec<TokenEntry> = vec![];
-        for c in cand {
-            v.push(c.clone());
+        "note-stdin" => {
+            note_lines(&mut stash, std::io::stdin().lock(), order);
         }
-        v
-    }
-
-    fn predict_all_string(&mut self, input: &str, context: usize) {
-        for i in (0..context).rev() {
-            if input.len() > i {
-                let v = self.predict_token(&input[input.len() - 1 - i..]);
-                if v.len() > 0 {
-                    debug!("Predicted  {:?} at length {}", &v, i);
-                    break;
-                }
-            }
+        "predict" => {
+            stash
+                .predict_all_string(&args.positional[1], order)
+                .unwrap();
         }
-    }
-
-    fn predict_all_string_return(&mut self, input: &str, context: usize) -> Option<char> {
-        for i in (0..context).rev() {
-            if input.len() > i {
-                let v = self.predict_token(&input[input.len() - 1 - i..]);
-                if v.len() > 0 {
-                    debug!("Predicted  {:?} at length {}", &v, i);
-
-                    if let Token::C(c) = v[self.rng.gen_range(0, v.len())].value {
-                        return Some(c);
-                        break;
-                    }
-                }
+        "dump" => {
+            let candidates = stash.candidates(&args.positional[1]).unwrap();
+            let total: f64 = candidates.iter().map(|e| e.count as f64).sum();
+            for e in &candidates {
+                let p = e.count as f64 / total;
+                println!("{}\t{}\t{:.4}", describe(&e.value), e.count, p);
             }
         }
-        return None;
-    }
-    fn generate(&mut self, input: &str, context: usize) {
-        let mut content = format!("{}", input);
-        print!("{}", input);
-        loop {
-            if let Some(c) = self.predict_all_string_return(&content, 64) {
-                print!("{}", c);
-                content = format!("{}{}", &content, c);
-            } else {
+        "generate" => {
+            let seed = &args.positional[1];
+            let out = stash.generate_string(seed, order, args.max_len).unwrap();
+            print!("{}{}", seed, out);
+            if args.trailing_newline {
                 println!("\n\n");
-                return;
             }
         }
-    }
-}
-
-fn main() {
-    env_logger::init();
-    debug!("this is a debug {}", "message");
-    let mut rng = rand::thread_rng();
-
-    let mut stash = TokenStash::new("data");
-
-    match std::env::args().nth(1).unwrap().as_str() {
-        "note" => {
-            stash.note_text(&std::env::args().nth(2).unwrap(), 32);
+        "perplexity" => {
+            let data = std::fs::read_to_string(&args.positional[1]).unwrap();
+            let perplexity = stash.perplexity(&data, order).unwrap();
+            // Perplexity averages over the predicted tokens, all but the first;
+            // spread their total over the characters they cover instead.
+            let predicted = stash.tokenize(&data).len().saturating_sub(1);
+            let nll = perplexity.ln() * predicted as f64;
+            let chars = data.chars().count().saturating_sub(1).max(1);
+            println!("perplexity\t{:.4}", perplexity);
+            println!("nll/char\t{:.4}", nll / chars as f64);
         }
-        "note-file" => {
-            let fname = std::env::args().nth(2).unwrap();
-            eprintln!("Noting {}...", &fname);
-            let data = std::fs::read_to_string(&fname).unwrap();
-            stash.note_text(&data, 32);
+        "prune" => {
+            let min_count = args.positional[1]
+                .parse()
+                .expect("prune needs a minimum count");
+            let removed = stash.prune(min_count).unwrap();
+            println!("removed {} entries", removed);
         }
-        "predict" => {
-            stash.predict_all_string(&std::env::args().nth(2).unwrap(), 32);
+        "repl" => {
+            repl::run(
+                &mut stash,
+                order,
+                std::io::stdin().lock(),
+                std::io::stdout(),
+            )
+            .unwrap();
         }
-        "generate" => {
-            stash.generate(&std::env::args().nth(2).unwrap(), 32);
+        "reset" => {
+            stash.clear().unwrap();
+        }
+        "serve" => {
+            serve::serve(&mut stash, order, args.port).unwrap();
+        }
+        "stats" => {
+            println!("{}", stash.stats().unwrap());
         }
         "test" => {
             test_db();
         }
-        x => {
-            panic!("{} is not a valid operation", x);
-        }
+        _ => unreachable!("check_usage accepted {}", command),
+    }
+    stash.close().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(args: &[&str]) -> Result<(), String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        check_usage(&args)
+    }
+
+    #[test]
+    fn missing_commands_and_arguments_are_reported() {
+        assert_eq!(check(&[]), Err("no command given".to_string()));
+        assert_eq!(
+            check(&["generate"]),
+            Err("generate needs an argument".to_string())
+        );
+        assert_eq!(
+            check(&["note-file"]),
+            Err("note-file needs an argument".to_string())
+        );
+        assert_eq!(
+            check(&["fly"]),
+            Err("fly is not a valid operation".to_string())
+        );
+        assert_eq!(check(&["generate", "the "]), Ok(()));
+        assert_eq!(check(&["note-file", "a.txt", "b.txt"]), Ok(()));
+        assert_eq!(check(&["stats"]), Ok(()));
     }
 }
@@ -0,0 +1,51 @@
+/// Rewrites text before it is normalized and tokenized, for training and prediction alike.
+///
+/// Like a custom [`Tokenizer`](crate::Tokenizer), a preprocessor is not
+/// recorded with the model, so set the same one every time it is opened.
+pub type Preprocessor = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Replace every run of whitespace with a single space, or with a single
+/// newline if the run contains one, so that line structure survives.
+pub fn collapse_whitespace(src: &str) -> String {
+    let mut out = String::with_capacity(src.len());
+    let mut run: Option<char> = None;
+    for c in src.chars() {
+        if c.is_whitespace() {
+            if c == '\n' || run.is_none() {
+                run = Some(if c == '\n' { '\n' } else { ' ' });
+            }
+        } else {
+            if let Some(ws) = run.take() {
+                out.push(ws);
+            }
+            out.push(c);
+        }
+    }
+    if let Some(ws) = run {
+        out.push(ws);
+    }
+    out
+}
+
+/// Drop control characters other than newlines and tabs.
+pub fn strip_control_chars(src: &str) -> String {
+    src.chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whitespace_runs_collapse_but_keep_newlines() {
+        assert_eq!(collapse_whitespace("  a \t b\r\n\n  c  "), " a b\nc ");
+        assert_eq!(collapse_whitespace(""), "");
+    }
+
+    #[test]
+    fn control_characters_are_stripped() {
+        assert_eq!(strip_control_chars("a\u{7}b\tc\nd\u{1b}"), "ab\tc\nd");
+    }
+}
@@ -0,0 +1,71 @@
+//! An interactive loop for the `repl` subcommand, keeping the model open between lines.
+
+use std::io::{BufRead, Write};
+
+use hashmem::TokenStash;
+
+/// The longest continuation generated for a `?` line.
+const MAX_LEN: usize = 200;
+
+/// Train on or generate from every line of `input` until it ends or `:quit` is read.
+///
+/// Lines starting with `?` print a continuation of the rest of the line;
+/// `:context N` and `:temperature T` change how later lines are handled. Any
+/// other line is trained on.
+pub fn run(
+    stash: &mut TokenStash,
+    mut order: usize,
+    mut input: impl BufRead,
+    mut out: impl Write,
+) -> hashmem::Result<()> {
+    let mut temperature = 1.0;
+    let mut line = String::new();
+    loop {
+        write!(out, "> ")?;
+        out.flush()?;
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if let Some(seed) = line.strip_prefix('?') {
+            let text = stash.generate_string_with_temperature(seed, order, temperature, MAX_LEN)?;
+            writeln!(out, "{}{}", seed, text)?;
+        } else if let Some(command) = line.strip_prefix(':') {
+            let mut parts = command.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("quit"), _) => break,
+                (Some("context"), Some(n)) => match n.parse() {
+                    Ok(n) => order = n,
+                    Err(_) => writeln!(out, "context must be a number")?,
+                },
+                (Some("temperature"), Some(t)) => match t.parse() {
+                    Ok(t) => temperature = t,
+                    Err(_) => writeln!(out, "temperature must be a number")?,
+                },
+                _ => writeln!(out, "commands: ?text, :context N, :temperature T, :quit")?,
+            }
+        } else {
+            stash.note_text(line, order)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_trained_on_and_questions_answered() {
+        let mut stash = TokenStash::in_memory();
+        let input = "abcd\n:context x\n:context 3\n?ab\n:quit\n?never\n";
+        let mut out = vec![];
+        run(&mut stash, 2, input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("context must be a number"));
+        assert!(out.contains("abcd\n"));
+        assert!(!out.contains("never"));
+        assert_eq!(stash.candidates("ab").unwrap()[0].count, 1);
+    }
+}
@@ -0,0 +1,275 @@
+//! A minimal HTTP/1.1 completion server for the `serve` subcommand.
+//!
+//! Requests are handled one at a time on the calling thread, and every
+//! connection is closed after its response.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use hashmem::{Token, TokenStash, DEFAULT_LOOP_LIMIT};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Largest request body accepted; longer ones are answered with 413.
+const MAX_BODY_BYTES: usize = 1 << 20;
+
+/// Longest request or header line accepted, in bytes.
+const MAX_LINE_BYTES: usize = 8 << 10;
+
+/// Most header lines accepted in one request.
+const MAX_HEADERS: usize = 100;
+
+/// Most tokens `/generate` produces, whatever `max_len` asks for.
+const MAX_GENERATE_LEN: usize = 10_000;
+
+/// How long a connection may stay silent before it is dropped, so an idle
+/// client cannot block the server.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request as read from the connection.
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct PredictRequest {
+    context: String,
+    k: usize,
+}
+
+#[derive(Deserialize)]
+struct GenerateRequest {
+    seed: String,
+    max_len: usize,
+}
+
+/// The text of `token` in a response.
+fn token_text(token: &Token) -> String {
+    match token {
+        Token::C(c) => c.to_string(),
+        Token::Num(n) => n.to_string(),
+        Token::Word(w) => w.clone(),
+        Token::Eos => "<eos>".to_string(),
+        Token::Bos => "<bos>".to_string(),
+        Token::Byte(b) => format!("0x{:02x}", b),
+    }
+}
+
+fn error(status: u16, message: &str) -> (u16, String) {
+    (status, json!({ "error": message }).to_string())
+}
+
+/// The status code and JSON body answering `method path` with `body`.
+fn handle(
+    stash: &mut TokenStash,
+    order: usize,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> (u16, String) {
+    if method != "POST" {
+        return error(405, "only POST is supported");
+    }
+    match path {
+        "/predict" => {
+            let req: PredictRequest = match serde_json::from_slice(body) {
+                Ok(req) => req,
+                Err(e) => return error(400, &e.to_string()),
+            };
+            match stash.candidates(&req.context) {
+                Ok(mut candidates) => {
+                    candidates.truncate(req.k);
+                    let candidates: Vec<_> = candidates
+                        .iter()
+                        .map(|e| json!({ "token": token_text(&e.value), "count": e.count }))
+                        .collect();
+                    (200, json!({ "candidates": candidates }).to_string())
+                }
+                Err(e) => error(500, &e.to_string()),
+            }
+        }
+        "/generate" => {
+            let req: GenerateRequest = match serde_json::from_slice(body) {
+                Ok(req) => req,
+                Err(e) => return error(400, &e.to_string()),
+            };
+            let max_len = req.max_len.min(MAX_GENERATE_LEN);
+            match stash.generate_string(&req.seed, order, max_len) {
+                Ok(text) => (200, json!({ "text": text }).to_string()),
+                Err(e) => error(500, &e.to_string()),
+            }
+        }
+        _ => error(404, "unknown endpoint"),
+    }
+}
+
+/// Read one line into `line`, returning whether it fit in [`MAX_LINE_BYTES`].
+fn read_line_capped<R: BufRead>(reader: &mut R, line: &mut String) -> std::io::Result<bool> {
+    let n = reader.take(MAX_LINE_BYTES as u64).read_line(line)?;
+    Ok(n < MAX_LINE_BYTES || line.ends_with('\n'))
+}
+
+/// Read a request from `reader`, or the response refusing it.
+fn read_request<R: BufRead>(reader: &mut R) -> std::io::Result<Result<Request, (u16, String)>> {
+    let mut request_line = String::new();
+    if !read_line_capped(reader, &mut request_line)? {
+        return Ok(Err(error(400, "request line too long")));
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("").to_string();
+
+    let mut content_length = 0;
+    let mut headers = 0;
+    loop {
+        let mut line = String::new();
+        if !read_line_capped(reader, &mut line)? {
+            return Ok(Err(error(431, "header line too long")));
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Ok(Err(error(431, "too many headers")));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Ok(Err(error(413, "request body too large")));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Ok(Request { method, path, body }))
+}
+
+/// Read one request from `stream` and write the response.
+fn respond(stash: &mut TokenStash, order: usize, stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let (status, response) = match read_request(&mut reader)? {
+        Ok(req) => handle(stash, order, &req.method, &req.path, &req.body),
+        Err(refusal) => refusal,
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        response.len(),
+        response
+    )
+}
+
+/// Serve `stash` on `port` until the process is stopped.
+pub fn serve(stash: &mut TokenStash, order: usize, port: u16) -> std::io::Result<()> {
+    // Requests are served one at a time, so a cycling model must not hold one up.
+    stash.set_loop_limit(Some(DEFAULT_LOOP_LIMIT));
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        if let Err(e) = stream.and_then(|stream| respond(stash, order, stream)) {
+            warn!("request failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints_answer_with_json() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abacab", 2).unwrap();
+
+        let body = br#"{"context": "a", "k": 1}"#;
+        let (status, response) = handle(&mut stash, 2, "POST", "/predict", body);
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(
+            response,
+            json!({ "candidates": [{ "token": "b", "count": 2 }] })
+        );
+
+        let body = br#"{"seed": "ab", "max_len": 10}"#;
+        let (status, response) = handle(&mut stash, 2, "POST", "/generate", body);
+        assert_eq!(status, 200);
+        assert_eq!(response, json!({ "text": "acabacabac" }).to_string());
+
+        assert_eq!(handle(&mut stash, 2, "POST", "/predict", b"{").0, 400);
+        assert_eq!(handle(&mut stash, 2, "POST", "/nowhere", b"").0, 404);
+        assert_eq!(handle(&mut stash, 2, "GET", "/predict", b"").0, 405);
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused_without_reading_them() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"POST /predict HTTP/1.1\r\nContent-Length: 99999999999999\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        let mut stash = TokenStash::in_memory();
+        let (stream, _) = listener.accept().unwrap();
+        respond(&mut stash, 2, stream).unwrap();
+        assert!(client
+            .join()
+            .unwrap()
+            .starts_with("HTTP/1.1 413 Payload Too Large"));
+    }
+
+    #[test]
+    fn generation_is_capped_whatever_max_len_asks() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abab", 2).unwrap();
+        let body = format!(r#"{{"seed": "ab", "max_len": {}}}"#, usize::MAX);
+        let (status, response) = handle(&mut stash, 2, "POST", "/generate", body.as_bytes());
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["text"].as_str().unwrap().len(), MAX_GENERATE_LEN);
+    }
+
+    #[test]
+    fn oversized_headers_are_refused() {
+        let status = |request: &[u8]| match read_request(&mut &request[..]).unwrap() {
+            Ok(_) => 200,
+            Err((status, _)) => status,
+        };
+        assert_eq!(status(b"POST /predict HTTP/1.1\r\nHost: x\r\n\r\n"), 200);
+        let long = format!(
+            "POST /predict HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_LINE_BYTES)
+        );
+        assert_eq!(status(long.as_bytes()), 431);
+        let many = format!(
+            "POST /predict HTTP/1.1\r\n{}\r\n",
+            "X: a\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert_eq!(status(many.as_bytes()), 431);
+        let path = format!("POST /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_BYTES));
+        assert_eq!(status(path.as_bytes()), 400);
+    }
+}
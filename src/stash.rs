@@ -0,0 +1,3038 @@
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::BufRead;
+use std::time::{Duration, Instant};
+
+mod codec;
+mod export;
+mod incremental;
+mod ledger;
+mod maintenance;
+mod nearest;
+mod scoring;
+mod search;
+mod snapshot;
+mod stats;
+mod upgrade;
+
+pub use export::ImportMode;
+pub use incremental::GenState;
+pub use stats::{ModelStats, OrderSize};
+
+use crate::error::Result;
+use crate::key::KeyStrategy;
+use crate::preprocess::Preprocessor;
+use crate::store::{
+    namespace_prefix, BloomStore, MemoryStore, RedbStore, SharedStore, Store, StoreOptions,
+};
+use crate::token::{total_count, EosMode, Normalization, Token, TokenEntry, TokenHits, TokenMode};
+use crate::tokenizer::{self, Tokenizer};
+
+/// Default maximum context length (n-gram order) for training and prediction.
+///
+/// A higher order gives more specific predictions but each context is seen
+/// less often, so predictions become sparser and back off more.
+pub const DEFAULT_ORDER: usize = 32;
+
+/// Probability used by [`TokenStash::perplexity`] for tokens the model never predicts.
+pub const DEFAULT_SMOOTHING_FLOOR: f64 = 1e-6;
+
+/// Weight kept per dropped context order by [`TokenStash::backoff_candidates`].
+pub const DEFAULT_BACKOFF_DISCOUNT: f64 = 0.4;
+
+/// How many of the most recent tokens [`TokenStash::generate_string_with_penalty`]
+/// treats as repeats.
+pub const DEFAULT_REPEAT_WINDOW: usize = 16;
+
+/// The [loop limit](TokenStash::set_loop_limit) of unbounded generation when
+/// none is set.
+pub const DEFAULT_LOOP_LIMIT: usize = 100;
+
+/// The characters after which [`TokenStash::generate_sentence`] stops.
+pub const DEFAULT_SENTENCE_ENDS: &[char] = &['.', '!', '?'];
+
+/// Number of contexts kept in memory before the cache is written back and emptied.
+pub const DEFAULT_CACHE_CAPACITY: usize = 100_000;
+
+/// Where every entry of a [`ContextHits`] sits, by token, so training finds
+/// the entry to update without scanning them all.
+///
+/// Only kept in memory: it encodes to nothing, so stored values are unchanged.
+/// It is built on first use and dropped whenever the entries are handed out
+/// for changes that bypass it.
+#[derive(Default, Debug, Clone)]
+struct EntryIndex(Option<HashMap<Token, usize>>);
+
+impl Encode for EntryIndex {
+    fn encode<E: Encoder>(&self, _: &mut E) -> std::result::Result<(), EncodeError> {
+        Ok(())
+    }
+}
+
+impl<Context> Decode<Context> for EntryIndex {
+    fn decode<D: Decoder<Context = Context>>(_: &mut D) -> std::result::Result<Self, DecodeError> {
+        Ok(Self::default())
+    }
+}
+
+bincode::impl_borrow_decode!(EntryIndex);
+
+/// The hits of one context, stored together with the context itself.
+#[derive(Debug, Clone, Encode, Decode)]
+struct ContextHits {
+    context: Vec<Token>,
+    hits: TokenHits,
+    index: EntryIndex,
+}
+
+impl ContextHits {
+    fn new(context: Vec<Token>, hits: TokenHits) -> Self {
+        ContextHits {
+            context,
+            hits,
+            index: EntryIndex::default(),
+        }
+    }
+
+    /// Add `count` observations of `value`, like [`add_count`] but through the index.
+    fn add_count(&mut self, value: &Token, count: u64) {
+        let entries = &mut self.hits.entries;
+        let index = self.index.0.get_or_insert_with(|| {
+            entries
+                .iter()
+                .enumerate()
+                .map(|(i, e)| (e.value.clone(), i))
+                .collect()
+        });
+        match index.get(value) {
+            Some(&i) => entries[i].count = entries[i].count.saturating_add(count),
+            None => {
+                index.insert(value.clone(), entries.len());
+                entries.push(TokenEntry {
+                    value: value.clone(),
+                    count,
+                });
+            }
+        }
+    }
+}
+
+/// Everything stored under one key.
+///
+/// Each context is kept in its own bucket and matched against the full token
+/// sequence, so contexts whose keys collide never share counts.
+#[derive(Default, Debug, Clone, Encode, Decode)]
+struct StoredHits {
+    buckets: Vec<ContextHits>,
+}
+
+impl StoredHits {
+    fn get(&self, context: &[Token]) -> Option<&TokenHits> {
+        self.buckets
+            .iter()
+            .find(|b| b.context == context)
+            .map(|b| &b.hits)
+    }
+
+    fn bucket_mut(&mut self, context: &[Token]) -> &mut ContextHits {
+        let pos = match self.buckets.iter().position(|b| b.context == context) {
+            Some(pos) => pos,
+            None => {
+                if !self.buckets.is_empty() {
+                    warn!(
+                        "key collision between {:?} and {:?}",
+                        context, self.buckets[0].context
+                    );
+                }
+                self.buckets
+                    .push(ContextHits::new(context.to_vec(), TokenHits::default()));
+                self.buckets.len() - 1
+            }
+        };
+        &mut self.buckets[pos]
+    }
+
+    fn get_or_insert(&mut self, context: &[Token]) -> &mut TokenHits {
+        let bucket = self.bucket_mut(context);
+        bucket.index = EntryIndex::default();
+        &mut bucket.hits
+    }
+
+    /// Add `count` observations of `value` after `context`.
+    ///
+    /// Finds the entry through the bucket's index, so repeated updates of a
+    /// context with many continuations stay cheap.
+    fn add_count(&mut self, context: &[Token], value: &Token, count: u64) {
+        self.bucket_mut(context).add_count(value, count);
+    }
+
+    /// Keep only the `max` most frequent entries of every non-empty context.
+    ///
+    /// Kept entries stay in their order; ties at the cut go to the ones seen
+    /// first. The empty context is left whole, as it holds the overall token
+    /// frequencies.
+    fn cap_branching(&mut self, max: usize) {
+        for bucket in &mut self.buckets {
+            let entries = &mut bucket.hits.entries;
+            if bucket.context.is_empty() || entries.len() <= max {
+                continue;
+            }
+            let mut order: Vec<usize> = (0..entries.len()).collect();
+            order.sort_by_key(|&i| std::cmp::Reverse(entries[i].count));
+            let mut keep = vec![false; entries.len()];
+            for &i in &order[..max] {
+                keep[i] = true;
+            }
+            let mut keep = keep.into_iter();
+            entries.retain(|_| keep.next().unwrap_or(false));
+            bucket.index = EntryIndex::default();
+        }
+    }
+}
+
+/// How many tokens [`TokenStash::note_text_with_progress`] counts between progress reports.
+pub const PROGRESS_INTERVAL: usize = 1000;
+
+/// Keys starting with this hold bookkeeping rather than contexts.
+///
+/// Context keys are hex digests or `r`-prefixed hex, so they never clash.
+const RESERVED_PREFIX: &str = "!";
+
+/// Write-back cache of recently touched keys.
+#[derive(Default, Debug, Clone)]
+struct TokenHitHash {
+    hits_by_hash: HashMap<String, StoredHits>,
+    dirty: HashSet<String>,
+}
+
+/// How often each token was sampled after each context during one generation.
+///
+/// Pairs are kept as 64-bit hashes, so a long generation does not hold on to
+/// every context it passed through.
+#[derive(Default, Debug, Clone)]
+struct LoopGuard {
+    repeats: HashMap<u64, usize>,
+}
+
+impl LoopGuard {
+    /// Count `token` following the last `context` of `tokens`, and tell whether
+    /// that has now happened more than `limit` times.
+    fn repeated(
+        &mut self,
+        tokens: &[Token],
+        context: usize,
+        token: &Token,
+        limit: Option<usize>,
+    ) -> bool {
+        let Some(limit) = limit else {
+            return false;
+        };
+        let mut hasher = DefaultHasher::new();
+        (&tokens[tokens.len().saturating_sub(context)..], token).hash(&mut hasher);
+        let seen = self.repeats.entry(hasher.finish()).or_insert(0);
+        *seen += 1;
+        *seen > limit
+    }
+}
+
+/// What generation does when no continuation is known for the current context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fallback {
+    /// End the generation.
+    #[default]
+    Stop,
+    /// Continue with a token sampled from the overall token frequencies.
+    RandomSeen,
+    /// Continue as if a new sequence started, from the `Token::Bos` context.
+    Restart,
+}
+
+/// Which candidate greedy decoding picks when several share the highest count.
+///
+/// Applies to [`complete_greedy`](TokenStash::complete_greedy) and to sampling
+/// at a temperature of zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TieBreak {
+    /// The candidate seen first in training.
+    #[default]
+    FirstSeen,
+    /// The smallest candidate in [`Token`] order.
+    ByToken,
+    /// A candidate drawn uniformly with the stash's RNG.
+    Random,
+}
+
+/// When training updates held in memory are persisted, bounding what a crash can lose.
+///
+/// Updates are counted in recorded token observations. Policies are checked as
+/// updates arrive; nothing is written in the background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Only when the cache is full, on [`flush`](TokenStash::flush) or on drop.
+    #[default]
+    WriteBack,
+    /// After every update, paying one write transaction each.
+    WriteThrough,
+    /// Once this many updates are buffered.
+    EveryUpdates(usize),
+    /// At the first update at least this long after the oldest buffered one.
+    Interval(Duration),
+}
+
+/// Why a generation ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// `Token::Eos` was sampled.
+    Eos,
+    /// No continuation was known and the [fallback](TokenStash::set_fallback) gave none.
+    NoCandidates,
+    /// The sampled token was less likely than the
+    /// [confidence threshold](TokenStash::set_confidence_threshold).
+    LowConfidence,
+    /// The length limit was reached.
+    MaxLength,
+    /// The same token followed the same context more often than the
+    /// [loop limit](TokenStash::set_loop_limit).
+    Repeating,
+}
+
+/// How one generation step was decided, from [`TokenStash::predict_trace`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PredictTrace {
+    /// Length of the context that produced the candidates, `None` if none did.
+    pub context_len: Option<usize>,
+    /// The candidates of that context, as stored.
+    pub candidates: Vec<TokenEntry>,
+    /// The token sampled from them.
+    pub chosen: Option<Token>,
+}
+
+/// How the next token is picked among the candidates of a context.
+enum Sampling {
+    Weighted,
+    /// Always the highest-count candidate.
+    Greedy,
+    Temperature(f64),
+    TopK(usize),
+    TopP(f64),
+    /// Sample from the stupid-backoff distribution with the given discount.
+    Backoff(f64),
+    /// Divide the weight of recently seen tokens by the given penalty.
+    Penalty(f64),
+    /// Favor rare candidates, as weighted by [`inverted`].
+    Inverse,
+}
+
+/// A trained model, by default backed by a redb database living under `prefix`.
+pub struct TokenStash {
+    prefix: String,
+    mode: TokenMode,
+    tokenizer: Box<dyn Tokenizer>,
+    preprocessor: Option<Preprocessor>,
+    eos_mode: EosMode,
+    bos: bool,
+    line_boundaries: bool,
+    min_context: usize,
+    confidence_threshold: f64,
+    fallback: Fallback,
+    tie_break: TieBreak,
+    max_branching: Option<usize>,
+    loop_limit: Option<usize>,
+    sentence_ends: Vec<char>,
+    normalization: Normalization,
+    key_strategy: KeyStrategy,
+    store: Box<dyn Store>,
+    flush_policy: FlushPolicy,
+    /// Updates recorded in the cache since the last flush, and when the first of them was.
+    pending_updates: usize,
+    pending_since: Instant,
+    /// Whether the store is known to carry the current format marker.
+    format_marked: bool,
+    cache: TokenHitHash,
+    cache_capacity: usize,
+    /// Every stored context with its hits, kept by
+    /// [`nearest_context`](Self::nearest_context) until the next write.
+    context_list: Option<Vec<(Vec<Token>, TokenHits)>>,
+    smoothing_floor: f64,
+    add_k: f64,
+    rng: StdRng,
+}
+
+/// Whether `key` belongs to one of the namespaced models in `namespaces`.
+fn is_foreign(namespaces: &HashSet<String>, key: &str) -> bool {
+    namespace_prefix(key).is_some_and(|prefix| namespaces.contains(prefix))
+}
+
+/// Add `count` observations of `value` to `hits`, appending a new entry if needed.
+///
+/// Counts saturate at `u64::MAX` rather than wrapping around to small values.
+fn add_count(hits: &mut TokenHits, value: &Token, count: u64) {
+    match hits.entries.iter_mut().find(|e| &e.value == value) {
+        Some(e) => e.count = e.count.saturating_add(count),
+        None => hits.entries.push(TokenEntry {
+            value: value.clone(),
+            count,
+        }),
+    }
+}
+
+/// Add every count in `from` to the matching entries of `into`.
+fn merge_hits(into: &mut TokenHits, from: &TokenHits) {
+    for e in &from.entries {
+        add_count(into, &e.value, e.count);
+    }
+}
+
+/// The `k` highest-count entries, most frequent first.
+///
+/// `k` is clamped to the number of entries, and a `k` of zero is treated as one.
+fn top_k(mut entries: Vec<TokenEntry>, k: usize) -> Vec<TokenEntry> {
+    entries.sort_by_key(|e| std::cmp::Reverse(e.count));
+    entries.truncate(k.max(1));
+    entries
+}
+
+/// `entries` reweighted so the rarest is the most likely: each count becomes
+/// `max_count - count + 1`, keeping every candidate possible.
+fn inverted(entries: Vec<TokenEntry>) -> Vec<TokenEntry> {
+    let max = entries.iter().map(|e| e.count).max().unwrap_or(0);
+    entries
+        .into_iter()
+        .map(|e| TokenEntry {
+            count: max.saturating_sub(e.count).saturating_add(1),
+            value: e.value,
+        })
+        .collect()
+}
+
+/// The smallest set of most frequent entries whose probability mass reaches `p`.
+///
+/// The most frequent entry is always kept, even if it alone exceeds `p`.
+fn top_p(mut entries: Vec<TokenEntry>, p: f64) -> Vec<TokenEntry> {
+    entries.sort_by_key(|e| std::cmp::Reverse(e.count));
+    let total = total_count(&entries);
+    let mut cumulative = 0u128;
+    let mut keep = 0;
+    for e in &entries {
+        cumulative += e.count as u128;
+        keep += 1;
+        if cumulative as f64 >= p * total as f64 {
+            break;
+        }
+    }
+    entries.truncate(keep.max(1));
+    entries
+}
+
+impl TokenStash {
+    /// Open (or create) the model stored in `{prefix}/db`.
+    pub fn new(prefix: &str) -> Result<Self> {
+        Self::with_mode(prefix, TokenMode::Char)
+    }
+
+    /// Open (or create) the model stored in `{prefix}/db`, tokenizing with `mode`.
+    ///
+    /// The same mode must be used for training and prediction on a given database.
+    pub fn with_mode(prefix: &str, mode: TokenMode) -> Result<Self> {
+        let rng = StdRng::from_entropy();
+        Self::open(prefix, mode, &StoreOptions::default(), rng)
+    }
+
+    /// Open (or create) the model stored in `{prefix}/db` with the given database settings.
+    pub fn with_options(prefix: &str, mode: TokenMode, options: &StoreOptions) -> Result<Self> {
+        Self::open(prefix, mode, options, StdRng::from_entropy())
+    }
+
+    /// Open (or create) the model stored in `{prefix}/db` with a deterministic RNG.
+    ///
+    /// Generating from the same input against the same database with the same
+    /// seed always produces the same output.
+    pub fn with_seed(prefix: &str, seed: u64) -> Result<Self> {
+        Self::open(
+            prefix,
+            TokenMode::Char,
+            &StoreOptions::default(),
+            Self::seeded_rng(seed),
+        )
+    }
+
+    /// Open (or create) the model stored in `{prefix}/db` under `namespace`.
+    ///
+    /// Models in different namespaces of one database never see each other's
+    /// statistics. A database can only be opened once at a time, so to use several
+    /// namespaces of it at once, open it as a [`SharedStore`] and pass each
+    /// [`namespace`](SharedStore::namespace) to [`with_store`](Self::with_store).
+    pub fn new_namespaced(prefix: &str, namespace: &str) -> Result<Self> {
+        let store = RedbStore::open(&format!("{}/db", &prefix))?;
+        let shared = SharedStore::new(Box::new(store));
+        let mut stash = Self::from_parts(
+            prefix.to_string(),
+            Box::new(shared.namespace(namespace)),
+            TokenMode::Char,
+            StdRng::from_entropy(),
+        );
+        stash.upgrade_format()?;
+        Ok(stash)
+    }
+
+    /// Reset the RNG to a deterministic state derived from `seed`.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Self::seeded_rng(seed);
+    }
+
+    fn seeded_rng(seed: u64) -> StdRng {
+        StdRng::seed_from_u64(seed)
+    }
+
+    /// A model kept entirely in memory, for tests and short-lived use.
+    pub fn in_memory() -> Self {
+        Self::with_store(Box::new(MemoryStore::default()), TokenMode::Char)
+    }
+
+    /// A model stored in `store`, tokenizing with `mode`.
+    ///
+    /// Its [`prefix`](Self::prefix) is empty.
+    pub fn with_store(store: Box<dyn Store>, mode: TokenMode) -> Self {
+        Self::from_parts(String::new(), store, mode, StdRng::from_entropy())
+    }
+
+    fn open(prefix: &str, mode: TokenMode, options: &StoreOptions, rng: StdRng) -> Result<Self> {
+        let store = RedbStore::open_with(&format!("{}/db", &prefix), options)?;
+        let store: Box<dyn Store> = match options.bloom_bits_per_key {
+            Some(bits) => Box::new(BloomStore::new(store, bits)?),
+            None => Box::new(store),
+        };
+        let mut stash = Self::from_parts(prefix.to_string(), store, mode, rng);
+        stash.upgrade_format()?;
+        Ok(stash)
+    }
+
+    fn from_parts(prefix: String, store: Box<dyn Store>, mode: TokenMode, rng: StdRng) -> Self {
+        TokenStash {
+            rng,
+            prefix,
+            mode,
+            tokenizer: tokenizer::for_mode(mode),
+            preprocessor: None,
+            eos_mode: EosMode::None,
+            bos: false,
+            line_boundaries: false,
+            min_context: 1,
+            confidence_threshold: 0.0,
+            fallback: Fallback::Stop,
+            tie_break: TieBreak::FirstSeen,
+            max_branching: None,
+            loop_limit: None,
+            sentence_ends: DEFAULT_SENTENCE_ENDS.to_vec(),
+            normalization: Normalization::None,
+            key_strategy: KeyStrategy::default(),
+            store,
+            flush_policy: FlushPolicy::WriteBack,
+            pending_updates: 0,
+            pending_since: Instant::now(),
+            format_marked: false,
+            cache: TokenHitHash::default(),
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            context_list: None,
+            smoothing_floor: DEFAULT_SMOOTHING_FLOOR,
+            add_k: 0.0,
+        }
+    }
+
+    /// Limit how many contexts are kept in memory between flushes.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache_capacity = capacity.max(1);
+    }
+
+    /// Set the probability [`perplexity`](Self::perplexity) assigns to unpredicted tokens.
+    ///
+    /// It is clamped to `f64::MIN_POSITIVE..=1.0` so that perplexity stays finite.
+    pub fn set_smoothing_floor(&mut self, floor: f64) {
+        self.smoothing_floor = floor.clamp(f64::MIN_POSITIVE, 1.0);
+    }
+
+    /// The directory this stash was opened from, empty when it has none.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Choose how contexts are turned into database keys.
+    ///
+    /// A database must always be used with the strategy it was trained with.
+    pub fn set_key_strategy(&mut self, key_strategy: KeyStrategy) {
+        self.key_strategy = key_strategy;
+    }
+
+    /// Choose where training inserts `Token::Eos` markers.
+    pub fn set_eos_mode(&mut self, eos_mode: EosMode) {
+        self.eos_mode = eos_mode;
+    }
+
+    /// Choose whether training records a `Token::Bos` marker before every sequence.
+    ///
+    /// A sequence is a line with [`EosMode::Line`] and a whole trained text
+    /// otherwise. With markers recorded, generating from an empty seed starts
+    /// from the learned distribution of sequence-initial tokens.
+    pub fn set_bos(&mut self, bos: bool) {
+        self.bos = bos;
+    }
+
+    /// Record `Token::Bos` before and `Token::Eos` after every trained document.
+    ///
+    /// Shorthand for [`set_bos`](Self::set_bos) together with
+    /// [`EosMode::Document`] (or, when `false`, turning both off), so that
+    /// generating from an empty seed produces a whole document from its
+    /// learned beginning to its learned end. Neither marker is ever part of
+    /// the generated text.
+    pub fn set_document_markers(&mut self, markers: bool) {
+        self.bos = markers;
+        self.eos_mode = if markers {
+            EosMode::Document
+        } else {
+            EosMode::None
+        };
+    }
+
+    /// Whether training treats every line as an independent sequence.
+    ///
+    /// With line boundaries no context reaches back past the start of its line,
+    /// so the end of one line is never learned as leading into the next. Each
+    /// line gets its own sequence markers as configured; with any [`EosMode`]
+    /// other than `None` every line ends with `Token::Eos`.
+    pub fn set_line_boundaries(&mut self, line_boundaries: bool) {
+        self.line_boundaries = line_boundaries;
+    }
+
+    /// Never predict from contexts shorter than `min_context` tokens (at least one).
+    ///
+    /// Prediction normally backs off to ever shorter suffixes of the input,
+    /// down to a single token, which can give near-random output for unfamiliar
+    /// input. With a larger minimum, inputs whose longer suffixes are all unknown
+    /// get no prediction instead, trading coverage for quality. This applies to
+    /// sampling, generation, backoff, beam search and scoring.
+    pub fn set_min_context(&mut self, min_context: usize) {
+        self.min_context = min_context.max(1);
+    }
+
+    /// Stop generating instead of emitting a token less likely than `threshold`.
+    ///
+    /// The likelihood of a sampled token is its share of the candidates of the
+    /// longest known context, or of the overall token frequencies when it came
+    /// from a [fallback](Self::set_fallback). The threshold is clamped to
+    /// `0.0..=1.0`; zero (the default) never stops early.
+    pub fn set_confidence_threshold(&mut self, threshold: f64) {
+        self.confidence_threshold = threshold.clamp(0.0, 1.0);
+    }
+
+    /// Choose when training updates are persisted instead of waiting for the cache to fill.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = policy;
+    }
+
+    /// Whether `updates` buffered since `since` must be persisted under the flush policy.
+    fn flush_due(&self, updates: usize, since: Instant) -> bool {
+        match self.flush_policy {
+            FlushPolicy::WriteBack => false,
+            FlushPolicy::WriteThrough => updates > 0,
+            FlushPolicy::EveryUpdates(n) => updates >= n,
+            FlushPolicy::Interval(interval) => updates > 0 && since.elapsed() >= interval,
+        }
+    }
+
+    /// Choose what generation does instead of stopping when nothing more is known.
+    ///
+    /// With anything but [`Fallback::Stop`], generation usually only ends at
+    /// `Token::Eos` or the length limit.
+    pub fn set_fallback(&mut self, fallback: Fallback) {
+        self.fallback = fallback;
+    }
+
+    /// Choose how greedy decoding settles ties between equally frequent candidates.
+    ///
+    /// Both [`TieBreak::FirstSeen`] and [`TieBreak::ByToken`] make greedy
+    /// output reproducible; only the latter is independent of training order.
+    pub fn set_tie_break(&mut self, tie_break: TieBreak) {
+        self.tie_break = tie_break;
+    }
+
+    /// Stop generating once the same token has been sampled after the same
+    /// context more than `limit` times.
+    ///
+    /// The context is the last `context` tokens passed to the generation
+    /// call, so a repeat means the output has entered a cycle it will not leave
+    /// on its own; generation then ends with [`StopReason::Repeating`]. With
+    /// `None`, the default, generation bounded by a `max_len` is left to run
+    /// its length, while unbounded generation such as [`generate`](Self::generate)
+    /// and [`step`](Self::step) still stops after [`DEFAULT_LOOP_LIMIT`] repeats,
+    /// so it cannot run forever. With a very short context, ordinary text
+    /// repeats pairs often, so use a generous limit.
+    pub fn set_loop_limit(&mut self, limit: Option<usize>) {
+        self.loop_limit = limit;
+    }
+
+    /// Store at most `max` continuations per context, or any number with `None`.
+    ///
+    /// This is lossy: whenever a context is written with more, only the `max`
+    /// most frequent are kept and the counts of the rest are discarded, so a
+    /// dropped continuation starts again from zero if it shows up later. It
+    /// bounds the size of short contexts such as a single space, which
+    /// otherwise grow with the vocabulary and slow down every update to them.
+    /// The overall token frequencies are never capped.
+    pub fn set_max_branching(&mut self, max: Option<usize>) {
+        self.max_branching = max.map(|max| max.max(1));
+    }
+
+    /// Choose how text is normalized before tokenizing, e.g. to ignore case.
+    ///
+    /// Generated text comes out normalized as well. Use the same normalization
+    /// for training and prediction on a given database.
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+    }
+
+    /// The tokenization mode this stash was opened with.
+    pub fn mode(&self) -> TokenMode {
+        self.mode
+    }
+
+    /// Replace the tokenizer of the [mode](Self::mode) with a custom one for training and generation.
+    ///
+    /// Raw input given as bytes is still split into bytes in [`TokenMode::Byte`].
+    pub fn set_tokenizer(&mut self, tokenizer: Box<dyn Tokenizer>) {
+        self.tokenizer = tokenizer;
+    }
+
+    /// Rewrite all text with `preprocessor` before it is normalized and tokenized.
+    ///
+    /// Training text and prediction input go through it alike, so a model
+    /// trained with a preprocessor sees the same cleaned form of its input
+    /// when predicting; `None` removes it. Use [`collapse_whitespace`],
+    /// [`strip_control_chars`] or any closure, for instance to strip markup.
+    /// Multi-line text may reach it one line at a time, and raw input in
+    /// [`TokenMode::Byte`] bypasses it.
+    ///
+    /// [`collapse_whitespace`]: crate::collapse_whitespace
+    /// [`strip_control_chars`]: crate::strip_control_chars
+    pub fn set_preprocessor(&mut self, preprocessor: Option<Preprocessor>) {
+        self.preprocessor = preprocessor;
+    }
+
+    /// Split `src` into tokens as training and prediction do, after
+    /// preprocessing and normalizing it.
+    ///
+    /// No sequence markers are added.
+    pub fn tokenize(&self, src: &str) -> Vec<Token> {
+        match &self.preprocessor {
+            Some(preprocess) => self
+                .tokenizer
+                .tokenize(&self.normalization.apply(&preprocess(src))),
+            None => self.tokenizer.tokenize(&self.normalization.apply(src)),
+        }
+    }
+
+    /// Tokenize raw input: bytes in [`TokenMode::Byte`], lossily decoded UTF-8 otherwise.
+    fn tokenize_bytes(&self, src: &[u8]) -> Vec<Token> {
+        match self.mode {
+            TokenMode::Byte => src.iter().copied().map(Token::Byte).collect(),
+            _ => self.tokenize(&String::from_utf8_lossy(src)),
+        }
+    }
+
+    /// `tokens` as one sequence, with the markers enabled for this stash around it.
+    fn mark_sequence(&self, tokens: Vec<Token>, eos: bool) -> Vec<Token> {
+        let mut marked = vec![];
+        if self.bos {
+            marked.push(Token::Bos);
+        }
+        marked.extend(tokens);
+        if eos {
+            marked.push(Token::Eos);
+        }
+        marked
+    }
+
+    /// Tokenize training text, inserting `Token::Bos` and `Token::Eos` markers as configured.
+    fn tokenize_for_training(&self, src: &str) -> Vec<Token> {
+        match self.eos_mode {
+            EosMode::Line => src
+                .split_inclusive('\n')
+                .flat_map(|line| self.mark_sequence(self.tokenize(line), true))
+                .collect(),
+            mode => self.mark_sequence(self.tokenize(src), mode == EosMode::Document),
+        }
+    }
+
+    /// Like [`tokenize_for_training`](Self::tokenize_for_training), for raw input.
+    fn tokenize_bytes_for_training(&self, src: &[u8]) -> Vec<Token> {
+        match self.eos_mode {
+            EosMode::Line => src
+                .split_inclusive(|b| *b == b'\n')
+                .flat_map(|line| self.mark_sequence(self.tokenize_bytes(line), true))
+                .collect(),
+            mode => self.mark_sequence(self.tokenize_bytes(src), mode == EosMode::Document),
+        }
+    }
+
+    /// One line tokenized as an independent sequence, for [line boundaries](Self::set_line_boundaries).
+    fn mark_line(&self, tokens: Vec<Token>) -> Vec<Token> {
+        self.mark_sequence(tokens, self.eos_mode != EosMode::None)
+    }
+
+    /// Training text as the token sequences that are trained independently.
+    fn training_sequences(&self, src: &str) -> Vec<Vec<Token>> {
+        if self.line_boundaries {
+            src.split_inclusive('\n')
+                .map(|line| self.mark_line(self.tokenize(line)))
+                .collect()
+        } else {
+            vec![self.tokenize_for_training(src)]
+        }
+    }
+
+    /// Like [`training_sequences`](Self::training_sequences), for raw input.
+    fn training_byte_sequences(&self, src: &[u8]) -> Vec<Vec<Token>> {
+        if self.line_boundaries {
+            src.split_inclusive(|b| *b == b'\n')
+                .map(|line| self.mark_line(self.tokenize_bytes(line)))
+                .collect()
+        } else {
+            vec![self.tokenize_bytes_for_training(src)]
+        }
+    }
+
+    /// The tokens generation continues from: `tokens`, or the start marker when empty.
+    fn seed_or_bos(tokens: Vec<Token>) -> Vec<Token> {
+        if tokens.is_empty() {
+            vec![Token::Bos]
+        } else {
+            tokens
+        }
+    }
+
+    fn seed_tokens(&self, input: &str) -> Vec<Token> {
+        Self::seed_or_bos(self.tokenize(input))
+    }
+
+    /// Append the text form of `token` to `out`, which already holds `previous` tokens.
+    fn render_token(&self, token: &Token, previous: usize, out: &mut String) {
+        let tokens = std::slice::from_ref(token);
+        out.push_str(&self.tokenizer.detokenize_continuation(tokens, previous));
+    }
+
+    /// The text form of `tokens`, which follow `previous` tokens.
+    fn render_tokens(&self, tokens: &[Token], previous: usize) -> String {
+        self.tokenizer.detokenize_continuation(tokens, previous)
+    }
+
+    /// The database key of the context `src` under this stash's [key strategy](Self::set_key_strategy).
+    ///
+    /// Keys start with the context length, so contexts of one order can be
+    /// listed without visiting the others.
+    pub fn hash_tokens(&self, src: &[Token]) -> String {
+        self.key_strategy.context_key(src)
+    }
+
+    fn read_hits_from_file(&self, hash: &str) -> Result<StoredHits> {
+        match self.store.get(hash)? {
+            Some(data) => Self::decode_hits(&data),
+            None => Ok(StoredHits::default()),
+        }
+    }
+
+    /// The key prefixes of [namespaced](SharedStore::namespace) models sharing
+    /// this stash's store, found by their format markers.
+    ///
+    /// A stash opened on a whole database sees their keys too, and must leave
+    /// them alone.
+    fn foreign_namespaces(&self) -> Result<HashSet<String>> {
+        let mut namespaces = HashSet::new();
+        self.store.for_each(&mut |key, _| {
+            if let Some(prefix) = namespace_prefix(key) {
+                if key[prefix.len()..] == *upgrade::FORMAT_KEY {
+                    namespaces.insert(prefix.to_string());
+                }
+            }
+            Ok(())
+        })?;
+        Ok(namespaces)
+    }
+
+    /// Call `f` with every key of this model and its value, in key order,
+    /// skipping the keys of namespaced models sharing the store.
+    fn for_each_own_key(&self, f: &mut dyn FnMut(&str, &[u8]) -> Result<()>) -> Result<()> {
+        let namespaces = self.foreign_namespaces()?;
+        self.store.for_each(&mut |key, value| {
+            if is_foreign(&namespaces, key) {
+                return Ok(());
+            }
+            f(key, value)
+        })
+    }
+
+    /// Call `f` with every stored key, context and its hits, after flushing the cache.
+    fn for_each_context<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &[Token], TokenHits) -> Result<()>,
+    {
+        self.flush()?;
+        self.for_each_own_key(&mut |key, value| {
+            if key.starts_with(RESERVED_PREFIX) {
+                return Ok(());
+            }
+            for bucket in Self::decode_hits(value)?.buckets {
+                f(key, &bucket.context, bucket.hits)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Every stored context with its key and hits, read lazily from the database.
+    ///
+    /// Updates still in the cache are not included; [`flush`](Self::flush)
+    /// first to see them. A value that cannot be decoded yields an error for
+    /// its key and iteration carries on with the next one. The bookkeeping
+    /// entries of the database and the keys of namespaced models sharing it
+    /// are skipped.
+    pub fn contexts(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<(String, Vec<Token>, TokenHits)>> + '_> {
+        let namespaces = self.foreign_namespaces()?;
+        Ok(self.store.iter()?.flat_map(move |item| {
+            let decoded = item.and_then(|(key, value)| {
+                if key.starts_with(RESERVED_PREFIX) || is_foreign(&namespaces, &key) {
+                    return Ok(vec![]);
+                }
+                let buckets = Self::decode_hits(&value)?.buckets;
+                Ok(buckets
+                    .into_iter()
+                    .map(|b| Ok((key.clone(), b.context, b.hits)))
+                    .collect())
+            });
+            match decoded {
+                Ok(contexts) => contexts,
+                Err(e) => vec![Err(e)],
+            }
+        }))
+    }
+
+    /// Like [`for_each_context`](Self::for_each_context), visiting only the contexts of `order` tokens.
+    fn for_each_context_of_order<F>(&mut self, order: usize, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &[Token], TokenHits) -> Result<()>,
+    {
+        self.flush()?;
+        let namespaces = self.foreign_namespaces()?;
+        self.store
+            .for_each_prefix(&format!("{}:", order), &mut |key, value| {
+                // Namespaces whose name is `order` characters long share the prefix.
+                if is_foreign(&namespaces, key) {
+                    return Ok(());
+                }
+                for bucket in Self::decode_hits(value)?.buckets {
+                    f(key, &bucket.context, bucket.hits)?;
+                }
+                Ok(())
+            })
+    }
+
+    fn encode_hits(hits: &StoredHits) -> Result<Vec<u8>> {
+        Ok(codec::encode(hits))
+    }
+
+    fn decode_hits(data: &[u8]) -> Result<StoredHits> {
+        codec::decode(data)
+    }
+
+    /// Persist every modified cached context in a single transaction.
+    ///
+    /// Once it returns the updates are durable: redb syncs every committed
+    /// transaction to disk before the commit completes.
+    pub fn flush(&mut self) -> Result<()> {
+        self.pending_updates = 0;
+        if self.cache.dirty.is_empty() {
+            return Ok(());
+        }
+        let mut changes = vec![];
+        for hash in &self.cache.dirty {
+            if let Some(hits) = self.cache.hits_by_hash.get_mut(hash) {
+                if let Some(max) = self.max_branching {
+                    hits.cap_branching(max);
+                }
+                changes.push((hash.clone(), Some(Self::encode_hits(hits)?)));
+            }
+        }
+        self.mark_format(&mut changes)?;
+        self.apply_changes(changes)?;
+        debug!("Flushed {} cached contexts", self.cache.dirty.len());
+        self.cache.dirty.clear();
+        Ok(())
+    }
+
+    /// Apply `changes` to the store, dropping the cached context list.
+    fn apply_changes(&mut self, changes: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+        self.context_list = None;
+        self.store.apply(changes)
+    }
+
+    /// Everything stored under `hash`, loading it from the database on a cache miss.
+    ///
+    /// When the cache is full it is written back and emptied before loading.
+    fn cached_hits(&mut self, hash: &str) -> Result<&mut StoredHits> {
+        if !self.cache.hits_by_hash.contains_key(hash) {
+            if self.cache.hits_by_hash.len() >= self.cache_capacity {
+                self.flush()?;
+                self.cache.hits_by_hash.clear();
+            }
+            let hits = self.read_hits_from_file(hash)?;
+            self.cache.hits_by_hash.insert(hash.to_string(), hits);
+        }
+        Ok(self.cache.hits_by_hash.get_mut(hash).unwrap())
+    }
+
+    /// Record a single observation of `next` following `current`.
+    ///
+    /// The update stays in the cache until [`flush`](Self::flush) is called.
+    pub fn note_next_token(&mut self, current: &[Token], next: &Token) -> Result<()> {
+        let hash = self.hash_tokens(current);
+        debug!("current: {:?} next: {:?}, hash: {}", current, next, &hash);
+        let stored = self.cached_hits(&hash)?;
+        debug!("Hits B4: {:?}", stored.get(current));
+        stored.add_count(current, next, 1);
+        debug!("Hits AF: {:?}", stored.get(current));
+        self.cache.dirty.insert(hash);
+        if self.pending_updates == 0 {
+            self.pending_since = Instant::now();
+        }
+        self.pending_updates += 1;
+        if self.flush_due(self.pending_updates, self.pending_since) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// The continuations of `current`, from the cache if it holds them.
+    ///
+    /// A cache miss reads the database without filling the cache, so lookups
+    /// only need `&self` and can run from several threads at once.
+    fn get_next_candidates(&self, current: &[Token]) -> Result<Vec<TokenEntry>> {
+        let hash = self.hash_tokens(current);
+        debug!("input: {:?} hash: {}", &current, &hash);
+        let entries = match self.cache.hits_by_hash.get(&hash) {
+            Some(stored) => stored.get(current).map(|h| h.entries.clone()),
+            None => self
+                .read_hits_from_file(&hash)?
+                .get(current)
+                .map(|h| h.entries.clone()),
+        };
+        debug!("hits: {:?}", &entries);
+        Ok(entries.unwrap_or_default())
+    }
+
+    /// Record that the last character of `input` followed the rest of it.
+    ///
+    /// Inputs shorter than two tokens carry no transition and are ignored.
+    pub fn note_string(&mut self, input: &str) -> Result<()> {
+        let input_tokenized = self.tokenize(input);
+        debug!("Tokenized: {:?}", &input_tokenized);
+        if input_tokenized.len() < 2 {
+            return Ok(());
+        }
+        let next = &input_tokenized[input_tokenized.len() - 1];
+        self.note_next_token(&input_tokenized[0..input_tokenized.len() - 1], next)?;
+        // The empty context holds the overall token frequencies.
+        self.note_next_token(&[], next)
+    }
+
+    /// Record the last token of `input` under every context length up to `context`.
+    ///
+    /// Contexts are cut from the tokenized input, never from the raw bytes, so
+    /// multi-byte characters are always kept whole.
+    pub fn note_all_string(&mut self, input: &str, context: usize) -> Result<()> {
+        let tokens = self.tokenize(input);
+        let Some((next, before)) = tokens.split_last() else {
+            return Ok(());
+        };
+        for order in 0..=context.min(before.len()) {
+            self.note_next_token(&before[before.len() - order..], next)?;
+        }
+        Ok(())
+    }
+
+    /// Train on `input` using contexts of up to `context` tokens.
+    pub fn note_text(&mut self, input: &str, context: usize) -> Result<()> {
+        self.note_text_batched(input, context)
+    }
+
+    /// Train on `input`, committing every update in a single write transaction.
+    ///
+    /// Pending updates are accumulated in memory first, so repeated contexts
+    /// within the same call see each other's counts.
+    pub fn note_text_batched(&mut self, input: &str, context: usize) -> Result<()> {
+        self.note_text_with_progress(input, context, |_, _| {})
+    }
+
+    /// Like [`note_text`](Self::note_text), calling `progress` with the number of
+    /// tokens processed so far and the total.
+    ///
+    /// It is called every [`PROGRESS_INTERVAL`] tokens and once more when all
+    /// tokens are counted, before the updates are written.
+    pub fn note_text_with_progress<F>(
+        &mut self,
+        input: &str,
+        context: usize,
+        mut progress: F,
+    ) -> Result<()>
+    where
+        F: FnMut(usize, usize),
+    {
+        let sequences = self.training_sequences(input);
+        self.note_tokens_batched(&sequences, context, &mut progress)
+    }
+
+    /// Train on already tokenized input, bypassing [`tokenize`](Self::tokenize).
+    ///
+    /// The tokens are trained exactly as given: no normalization, line
+    /// boundaries or sequence markers are applied, so include `Token::Bos` and
+    /// `Token::Eos` yourself where sequences start and end.
+    pub fn note_tokens(&mut self, tokens: &[Token], context: usize) -> Result<()> {
+        self.note_tokens_batched(&[tokens.to_vec()], context, &mut |_, _| {})
+    }
+
+    /// Train on raw `input`, one token per byte in [`TokenMode::Byte`].
+    ///
+    /// In the other modes the input is decoded as UTF-8 first, replacing
+    /// invalid sequences.
+    pub fn note_bytes(&mut self, input: &[u8], context: usize) -> Result<()> {
+        let sequences = self.training_byte_sequences(input);
+        self.note_tokens_batched(&sequences, context, &mut |_, _| {})
+    }
+
+    fn note_tokens_batched(
+        &mut self,
+        sequences: &[Vec<Token>],
+        context: usize,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        let batch = self.count_sequences(sequences, context, progress)?;
+        self.write_batch(batch)
+    }
+
+    /// The updated hits of every context in `sequences`, without writing them.
+    fn count_sequences(
+        &mut self,
+        sequences: &[Vec<Token>],
+        context: usize,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<HashMap<String, StoredHits>> {
+        // Start from what is on disk, including anything still cached.
+        self.flush()?;
+        let mut batch: HashMap<String, StoredHits> = HashMap::new();
+        let total: usize = sequences.iter().map(|s| s.len()).sum();
+        let mut offset = 0;
+        for tokens in sequences {
+            let mut report = |i: usize, _| {
+                let processed = offset + i + 1;
+                if processed.is_multiple_of(PROGRESS_INTERVAL) {
+                    progress(processed, total);
+                }
+            };
+            self.count_transitions(tokens, context, 0, &mut batch, &mut report)?;
+            offset += tokens.len();
+        }
+        progress(total, total);
+        Ok(batch)
+    }
+
+    /// Train on text read from `reader`, one line at a time.
+    ///
+    /// Only the current line and the last `context` tokens before it are held in
+    /// memory, and updates are written whenever the cache capacity is reached, so
+    /// arbitrarily large streams can be learned. Reading whole lines also keeps
+    /// multi-byte characters, numbers and words from being split at buffer
+    /// boundaries. The result is the same as [`note_text`](Self::note_text) on the
+    /// whole input.
+    pub fn note_reader<R: BufRead>(&mut self, reader: R, context: usize) -> Result<()> {
+        self.note_reader_with_progress(reader, context, |_| {})
+    }
+
+    /// Like [`note_reader`](Self::note_reader), calling `progress` with the
+    /// number of lines trained so far.
+    ///
+    /// It is called every [`PROGRESS_INTERVAL`] lines and once more at the end
+    /// of the input, after the updates are written.
+    pub fn note_reader_with_progress<R, F>(
+        &mut self,
+        mut reader: R,
+        context: usize,
+        mut progress: F,
+    ) -> Result<()>
+    where
+        R: BufRead,
+        F: FnMut(u64),
+    {
+        self.flush()?;
+        let mut batch: HashMap<String, StoredHits> = HashMap::new();
+        let mut window: Vec<Token> = vec![];
+        let mut line = String::new();
+        let mut lines = 0u64;
+        let mut buffered = 0;
+        let mut buffered_since = Instant::now();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            if self.line_boundaries {
+                window.clear();
+            }
+            let start = window.len();
+            match self.eos_mode {
+                _ if self.line_boundaries => window.extend(self.mark_line(self.tokenize(&line))),
+                EosMode::Line => window.extend(self.tokenize_for_training(&line)),
+                // The document starts at the first line and ends at the end of the stream.
+                _ if lines == 0 => window.extend(self.mark_sequence(self.tokenize(&line), false)),
+                _ => window.extend(self.tokenize(&line)),
+            }
+            self.count_transitions(&window, context, start, &mut batch, &mut |_, _| {})?;
+            if buffered == 0 {
+                buffered_since = Instant::now();
+            }
+            buffered += window.len() - start;
+            let excess = window.len().saturating_sub(context);
+            window.drain(..excess);
+            if batch.len() >= self.cache_capacity || self.flush_due(buffered, buffered_since) {
+                self.write_batch(std::mem::take(&mut batch))?;
+                buffered = 0;
+            }
+            lines += 1;
+            if lines.is_multiple_of(PROGRESS_INTERVAL as u64) {
+                progress(lines);
+            }
+        }
+        if self.eos_mode == EosMode::Document && !self.line_boundaries {
+            let start = window.len();
+            window.push(Token::Eos);
+            self.count_transitions(&window, context, start, &mut batch, &mut |_, _| {})?;
+        }
+        self.write_batch(batch)?;
+        progress(lines);
+        Ok(())
+    }
+
+    /// Call `f` with every transition in `tokens` from index `start` on.
+    ///
+    /// Each token is a continuation of the 0..=`context` tokens before it, the
+    /// empty context counting every token regardless of what precedes it.
+    /// `progress` is called with the index of every token and the total.
+    fn for_each_transition(
+        tokens: &[Token],
+        context: usize,
+        start: usize,
+        progress: &mut dyn FnMut(usize, usize),
+        f: &mut dyn FnMut(&[Token], &Token) -> Result<()>,
+    ) -> Result<()> {
+        let total = tokens.len();
+        for i in start.max(1)..tokens.len() {
+            let next = &tokens[i];
+            // Start markers are only ever contexts, never continuations.
+            if *next == Token::Bos {
+                progress(i, total);
+                continue;
+            }
+            for order in 0..=context.min(i) {
+                f(&tokens[i - order..i], next)?;
+            }
+            progress(i, total);
+        }
+        Ok(())
+    }
+
+    /// Count every transition in `tokens` from index `start` on into `batch`.
+    ///
+    /// Contexts missing from `batch` are first loaded from the database.
+    fn count_transitions(
+        &self,
+        tokens: &[Token],
+        context: usize,
+        start: usize,
+        batch: &mut HashMap<String, StoredHits>,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        Self::for_each_transition(tokens, context, start, progress, &mut |current, next| {
+            let hash = self.hash_tokens(current);
+            let stored = match batch.entry(hash) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    let stored = self.read_hits_from_file(e.key())?;
+                    e.insert(stored)
+                }
+            };
+            stored.add_count(current, next, 1);
+            Ok(())
+        })
+    }
+
+    /// Train on `text`, keeping only the transitions seen at least `min_count` times in it.
+    ///
+    /// A first pass counts the whole text in memory without touching the
+    /// database; the second adds only the frequent transitions to the stored
+    /// counts, so the long tail is never written. That gives a smaller model
+    /// than training and then [pruning](Self::prune), at the cost of holding
+    /// the counts of `text` in memory. The overall token frequencies are
+    /// filtered the same way.
+    pub fn train_two_pass(&mut self, text: &str, context: usize, min_count: u64) -> Result<()> {
+        let mut counted: HashMap<String, StoredHits> = HashMap::new();
+        for tokens in self.training_sequences(text) {
+            Self::for_each_transition(
+                &tokens,
+                context,
+                0,
+                &mut |_, _| {},
+                &mut |current, next| {
+                    let stored = counted.entry(self.hash_tokens(current)).or_default();
+                    stored.add_count(current, next, 1);
+                    Ok(())
+                },
+            )?;
+        }
+
+        self.flush()?;
+        let mut batch: HashMap<String, StoredHits> = HashMap::new();
+        for (hash, hits) in counted {
+            for bucket in hits.buckets {
+                let frequent: Vec<&TokenEntry> = bucket
+                    .hits
+                    .entries
+                    .iter()
+                    .filter(|e| e.count >= min_count)
+                    .collect();
+                if frequent.is_empty() {
+                    continue;
+                }
+                let stored = match batch.entry(hash.clone()) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => {
+                        let stored = self.read_hits_from_file(e.key())?;
+                        e.insert(stored)
+                    }
+                };
+                for e in frequent {
+                    stored.add_count(&bucket.context, &e.value, e.count);
+                }
+            }
+        }
+        self.write_batch(batch)
+    }
+
+    /// Write all of `batch` at once and refresh cached copies.
+    fn write_batch(&mut self, batch: HashMap<String, StoredHits>) -> Result<()> {
+        self.write_batch_with(batch, vec![])
+    }
+
+    /// Like [`write_batch`](Self::write_batch), applying `changes` in the same
+    /// transaction.
+    fn write_batch_with(
+        &mut self,
+        mut batch: HashMap<String, StoredHits>,
+        mut changes: Vec<(String, Option<Vec<u8>>)>,
+    ) -> Result<()> {
+        for (hash, hits) in &mut batch {
+            if let Some(max) = self.max_branching {
+                hits.cap_branching(max);
+            }
+            changes.push((hash.clone(), Some(Self::encode_hits(hits)?)));
+        }
+        self.mark_format(&mut changes)?;
+        self.apply_changes(changes)?;
+        for (hash, hits) in batch {
+            if let Some(cached) = self.cache.hits_by_hash.get_mut(&hash) {
+                *cached = hits;
+            }
+        }
+        Ok(())
+    }
+
+    /// The continuations observed after exactly each of `inputs`, in the same order.
+    ///
+    /// Equivalent to calling [`predict_token`](Self::predict_token) for every
+    /// input, but contexts missing from the cache are fetched from the store
+    /// in a single batch (one read transaction for redb), so serving many
+    /// prefixes at once costs much less than looking them up one by one.
+    pub fn predict_batch(&self, inputs: &[&str]) -> Result<Vec<Vec<TokenEntry>>> {
+        let contexts: Vec<Vec<Token>> = inputs.iter().map(|input| self.tokenize(input)).collect();
+        let hashes: Vec<String> = contexts.iter().map(|c| self.hash_tokens(c)).collect();
+        let misses: Vec<&str> = hashes
+            .iter()
+            .filter(|hash| !self.cache.hits_by_hash.contains_key(*hash))
+            .map(String::as_str)
+            .collect();
+        let mut fetched = HashMap::new();
+        for (hash, value) in misses.iter().zip(self.store.get_many(&misses)?) {
+            if let Some(data) = value {
+                fetched.insert(*hash, Self::decode_hits(&data)?);
+            }
+        }
+        Ok(contexts
+            .iter()
+            .zip(&hashes)
+            .map(|(context, hash)| {
+                self.cache
+                    .hits_by_hash
+                    .get(hash)
+                    .or_else(|| fetched.get(hash.as_str()))
+                    .and_then(|stored| stored.get(context))
+                    .map(|hits| hits.entries.clone())
+                    .unwrap_or_default()
+            })
+            .collect())
+    }
+
+    /// All continuations observed after exactly `input`.
+    pub fn predict_token(&self, input: &str) -> Result<Vec<TokenEntry>> {
+        let input_tokenized = self.tokenize(input);
+        let cand = self.get_next_candidates(&input_tokenized)?;
+        debug!("Candidates for {:?} : {:?}", &input_tokenized, &cand);
+        Ok(cand)
+    }
+
+    /// All continuations observed after exactly the context `tokens`.
+    ///
+    /// The counterpart of [`note_tokens`](Self::note_tokens) for pre-tokenized input.
+    pub fn predict_from_tokens(&self, tokens: &[Token]) -> Result<Vec<TokenEntry>> {
+        self.get_next_candidates(tokens)
+    }
+
+    /// All continuations observed after exactly `input`, most frequent first.
+    ///
+    /// Entries with equal counts keep the order in which they were first seen.
+    pub fn candidates(&self, input: &str) -> Result<Vec<TokenEntry>> {
+        let mut cand = self.predict_token(input)?;
+        cand.sort_by_key(|e| std::cmp::Reverse(e.count));
+        Ok(cand)
+    }
+
+    /// All continuations observed after exactly `input` with their probability, most likely first.
+    ///
+    /// The probabilities sum to one; an unknown `input` gives an empty list.
+    pub fn predict_token_probs(&self, input: &str) -> Result<Vec<(Token, f64)>> {
+        let cand = self.candidates(input)?;
+        let total = total_count(&cand);
+        if total == 0 {
+            return Ok(vec![]);
+        }
+        Ok(cand
+            .into_iter()
+            .map(|e| (e.value, e.count as f64 / total as f64))
+            .collect())
+    }
+
+    /// Take one generation step after `input` without generating, reporting how it was taken.
+    ///
+    /// The candidates come from the longest known context of up to `context`
+    /// tokens, as in [`generate_string`](Self::generate_string), and one is
+    /// sampled by weight. The [fallback](Self::set_fallback) is not applied, so
+    /// an unknown context gives an empty trace.
+    pub fn predict_trace(&mut self, input: &str, context: usize) -> Result<PredictTrace> {
+        let tokens = self.seed_tokens(input);
+        let Some((i, candidates)) = self.longest_candidates(&tokens, context)? else {
+            return Ok(PredictTrace {
+                context_len: None,
+                candidates: vec![],
+                chosen: None,
+            });
+        };
+        let chosen = self.weighted_choice(&candidates).map(|e| e.value.clone());
+        Ok(PredictTrace {
+            context_len: Some(i + 1),
+            candidates,
+            chosen,
+        })
+    }
+
+    /// How often every token was observed in training regardless of context,
+    /// most frequent first.
+    ///
+    /// These counts are stored as the continuations of the empty context, under
+    /// that context's key, and are kept up to date by every training method.
+    /// Calling [`note_next_token`](Self::note_next_token) directly only updates
+    /// them when it is given the empty context.
+    pub fn unigram_counts(&self) -> Result<Vec<TokenEntry>> {
+        let mut counts = self.get_next_candidates(&[])?;
+        counts.sort_by_key(|e| std::cmp::Reverse(e.count));
+        Ok(counts)
+    }
+
+    /// The `k` most frequent continuations observed after exactly `input`.
+    ///
+    /// With `k` of one this is the greedy choice; a `k` larger than the number
+    /// of candidates returns all of them.
+    pub fn predict_token_top_k(&self, input: &str, k: usize) -> Result<Vec<TokenEntry>> {
+        Ok(top_k(self.predict_token(input)?, k))
+    }
+
+    /// The candidates of the longest suffix of `tokens` (up to `context` long) that has any.
+    ///
+    /// Suffixes shorter than the [minimum context](Self::set_min_context) are not tried.
+    fn longest_candidates(
+        &self,
+        tokens: &[Token],
+        context: usize,
+    ) -> Result<Option<(usize, Vec<TokenEntry>)>> {
+        for i in (self.min_context - 1..context).rev() {
+            if tokens.len() > i {
+                let v = self.get_next_candidates(&tokens[tokens.len() - 1 - i..])?;
+                if !v.is_empty() {
+                    debug!("Predicted  {:?} at length {}", &v, i);
+                    return Ok(Some((i, v)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stupid-backoff scores for every continuation of the last `context` tokens.
+    ///
+    /// Starting from the longest suffix, each continuation scores its relative
+    /// frequency at the longest order it was seen at, multiplied by `discount`
+    /// once per order dropped to reach it.
+    fn backoff_distribution(
+        &self,
+        tokens: &[Token],
+        context: usize,
+        discount: f64,
+    ) -> Result<Vec<(Token, f64)>> {
+        let mut scores: Vec<(Token, f64)> = vec![];
+        let mut seen: HashSet<Token> = HashSet::new();
+        let mut weight = 1.0;
+        for order in (self.min_context..=context.min(tokens.len())).rev() {
+            let entries = self.get_next_candidates(&tokens[tokens.len() - order..])?;
+            let total = total_count(&entries);
+            for e in entries {
+                if total > 0 && seen.insert(e.value.clone()) {
+                    scores.push((e.value, weight * e.count as f64 / total as f64));
+                }
+            }
+            weight *= discount;
+        }
+        let sum: f64 = scores.iter().map(|(_, s)| s).sum();
+        if sum > 0.0 {
+            for (_, s) in &mut scores {
+                *s /= sum;
+            }
+        }
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(scores)
+    }
+
+    /// Continuations of `input` merged across all context lengths up to `context`
+    /// with stupid backoff, as probabilities summing to one, most likely first.
+    ///
+    /// A continuation seen after the longest known suffix keeps its relative
+    /// frequency; one only seen after shorter suffixes is scaled by `discount`
+    /// (typically [`DEFAULT_BACKOFF_DISCOUNT`]) for every order dropped. A weak
+    /// long-context signal therefore still outweighs short-context guesses.
+    pub fn backoff_candidates(
+        &self,
+        input: &str,
+        context: usize,
+        discount: f64,
+    ) -> Result<Vec<(Token, f64)>> {
+        let tokens = self.tokenize(input);
+        self.backoff_distribution(&tokens, context, discount)
+    }
+
+    /// Whether anything has been learned yet, including updates not flushed so far.
+    pub fn is_trained(&self) -> Result<bool> {
+        let cached = self
+            .cache
+            .hits_by_hash
+            .values()
+            .any(|h| !h.buckets.is_empty());
+        Ok(cached || !self.store.is_empty()?)
+    }
+
+    /// Log a warning when nothing could be predicted because the model is empty.
+    fn warn_if_untrained(&self) -> Result<()> {
+        if !self.is_trained()? {
+            warn!("the model has no data; train it before predicting or generating");
+        }
+        Ok(())
+    }
+
+    /// Log the candidates for the longest known suffix of `input`.
+    ///
+    /// Logs a warning instead when the model has not been trained.
+    pub fn predict_all_string(&self, input: &str, context: usize) -> Result<()> {
+        let tokens = self.tokenize(input);
+        if self.longest_candidates(&tokens, context)?.is_none() {
+            self.warn_if_untrained()?;
+        }
+        Ok(())
+    }
+
+    /// Pick one of `entries` with probability proportional to its count.
+    fn weighted_choice<'a>(&mut self, entries: &'a [TokenEntry]) -> Option<&'a TokenEntry> {
+        let total = total_count(entries);
+        if total == 0 {
+            return None;
+        }
+        let mut pick = self.rng.gen_range(0..total);
+        for e in entries {
+            if pick < e.count as u128 {
+                return Some(e);
+            }
+            pick -= e.count as u128;
+        }
+        None
+    }
+
+    /// The highest-count entry, settling ties as set with [`set_tie_break`](Self::set_tie_break).
+    fn most_frequent<'a>(&mut self, entries: &'a [TokenEntry]) -> Option<&'a TokenEntry> {
+        let best = entries.iter().map(|e| e.count).max()?;
+        let mut tied = entries.iter().filter(|e| e.count == best);
+        match self.tie_break {
+            TieBreak::FirstSeen => tied.next(),
+            TieBreak::ByToken => tied.min_by(|a, b| a.value.cmp(&b.value)),
+            TieBreak::Random => {
+                let tied: Vec<_> = tied.collect();
+                Some(tied[self.rng.gen_range(0..tied.len())])
+            }
+        }
+    }
+
+    /// Pick one of `entries` after raising the normalized counts to `1 / temperature`.
+    ///
+    /// A temperature of zero (or below) always picks the highest-count entry,
+    /// settling ties as set with [`set_tie_break`](Self::set_tie_break).
+    fn temperature_choice<'a>(
+        &mut self,
+        entries: &'a [TokenEntry],
+        temperature: f64,
+    ) -> Option<&'a TokenEntry> {
+        if temperature <= f64::EPSILON {
+            return self.most_frequent(entries);
+        }
+        let total = total_count(entries);
+        if total == 0 {
+            return None;
+        }
+        let weights: Vec<f64> = entries
+            .iter()
+            .map(|e| (e.count as f64 / total as f64).powf(1.0 / temperature))
+            .collect();
+        self.weighted_index(&weights).map(|i| &entries[i])
+    }
+
+    /// Pick an index with probability proportional to its weight.
+    fn weighted_index(&mut self, weights: &[f64]) -> Option<usize> {
+        if weights.is_empty() {
+            return None;
+        }
+        let sum: f64 = weights.iter().sum();
+        let mut pick = self.rng.gen::<f64>() * sum;
+        for (i, w) in weights.iter().enumerate() {
+            if pick < *w {
+                return Some(i);
+            }
+            pick -= w;
+        }
+        Some(weights.len() - 1)
+    }
+
+    fn choose(&mut self, entries: Vec<TokenEntry>, sampling: &Sampling) -> Option<Token> {
+        let chosen = match sampling {
+            Sampling::Weighted => self.weighted_choice(&entries),
+            Sampling::Greedy => self.most_frequent(&entries),
+            Sampling::Temperature(t) => self.temperature_choice(&entries, *t),
+            Sampling::TopK(k) => {
+                let entries = top_k(entries, *k);
+                return self.weighted_choice(&entries).map(|e| e.value.clone());
+            }
+            Sampling::TopP(p) => {
+                let entries = top_p(entries, *p);
+                return self.weighted_choice(&entries).map(|e| e.value.clone());
+            }
+            Sampling::Inverse => {
+                let entries = inverted(entries);
+                return self.weighted_choice(&entries).map(|e| e.value.clone());
+            }
+            Sampling::Backoff(_) | Sampling::Penalty(_) => {
+                unreachable!("sampled in predict_sampled")
+            }
+        };
+        chosen.map(|e| e.value.clone())
+    }
+
+    fn predict_sampled(
+        &mut self,
+        tokens: &[Token],
+        context: usize,
+        sampling: &Sampling,
+    ) -> Result<Option<Token>> {
+        if let Sampling::Backoff(discount) = sampling {
+            let scores = self.backoff_distribution(tokens, context, *discount)?;
+            let weights: Vec<f64> = scores.iter().map(|(_, s)| *s).collect();
+            return Ok(self.weighted_index(&weights).map(|i| scores[i].0.clone()));
+        }
+        if let Sampling::Penalty(penalty) = sampling {
+            let Some((_, entries)) = self.longest_candidates(tokens, context)? else {
+                return Ok(None);
+            };
+            let recent = &tokens[tokens.len().saturating_sub(DEFAULT_REPEAT_WINDOW)..];
+            let penalty = penalty.max(f64::MIN_POSITIVE);
+            let weights: Vec<f64> = entries
+                .iter()
+                .map(|e| {
+                    let weight = e.count as f64;
+                    if recent.contains(&e.value) {
+                        weight / penalty
+                    } else {
+                        weight
+                    }
+                })
+                .collect();
+            return Ok(self
+                .weighted_index(&weights)
+                .map(|i| entries[i].value.clone()));
+        }
+        Ok(match self.longest_candidates(tokens, context)? {
+            Some((_, v)) => self.choose(v, sampling),
+            None => None,
+        })
+    }
+
+    /// Predict the next token after `tokens`, applying the fallback when nothing is known.
+    ///
+    /// With [`Fallback::Restart`], `tokens` is replaced by the start marker.
+    fn predict_with_fallback(
+        &mut self,
+        tokens: &mut Vec<Token>,
+        context: usize,
+        sampling: &Sampling,
+    ) -> Result<Option<Token>> {
+        if let Some(t) = self.predict_sampled(tokens, context, sampling)? {
+            return Ok(Some(t));
+        }
+        match self.fallback {
+            Fallback::Stop => Ok(None),
+            Fallback::RandomSeen => {
+                let seen = self.get_next_candidates(&[])?;
+                Ok(self.weighted_choice(&seen).map(|e| e.value.clone()))
+            }
+            Fallback::Restart if tokens.as_slice() != [Token::Bos] => {
+                *tokens = vec![Token::Bos];
+                self.predict_sampled(tokens, context, sampling)
+            }
+            Fallback::Restart => Ok(None),
+        }
+    }
+
+    fn generate_sampled(
+        &mut self,
+        input: &str,
+        context: usize,
+        max_len: usize,
+        sampling: &Sampling,
+    ) -> Result<String> {
+        let tokens = self.seed_tokens(input);
+        let previous = tokens.iter().filter(|t| **t != Token::Bos).count();
+        let (generated, _) = self.generate_tokens(tokens, context, max_len, sampling)?;
+        Ok(self.render_tokens(&generated, previous))
+    }
+
+    /// Whether `token`, just predicted after `tokens`, is below the confidence threshold.
+    fn below_confidence(&self, tokens: &[Token], context: usize, token: &Token) -> Result<bool> {
+        if self.confidence_threshold <= 0.0 {
+            return Ok(false);
+        }
+        let entries = match self.longest_candidates(tokens, context)? {
+            Some((_, entries)) => entries,
+            // Fallback tokens come from the overall frequencies.
+            None => self.get_next_candidates(&[])?,
+        };
+        Ok(scoring::share(&entries, token) < self.confidence_threshold)
+    }
+
+    /// The loop limit of generation stopping after `max_len` tokens, which is
+    /// `usize::MAX` when unbounded.
+    fn loop_limit_for(&self, max_len: usize) -> Option<usize> {
+        self.loop_limit
+            .or((max_len == usize::MAX).then_some(DEFAULT_LOOP_LIMIT))
+    }
+
+    /// Up to `max_len` tokens continuing `tokens`, and why generation stopped.
+    fn generate_tokens(
+        &mut self,
+        mut tokens: Vec<Token>,
+        context: usize,
+        max_len: usize,
+        sampling: &Sampling,
+    ) -> Result<(Vec<Token>, StopReason)> {
+        let mut generated = vec![];
+        let mut guard = LoopGuard::default();
+        let loop_limit = self.loop_limit_for(max_len);
+        for _ in 0..max_len {
+            match self.predict_with_fallback(&mut tokens, context, sampling)? {
+                Some(Token::Eos) => return Ok((generated, StopReason::Eos)),
+                Some(t) => {
+                    if self.below_confidence(&tokens, context, &t)? {
+                        return Ok((generated, StopReason::LowConfidence));
+                    }
+                    if guard.repeated(&tokens, context, &t, loop_limit) {
+                        return Ok((generated, StopReason::Repeating));
+                    }
+                    generated.push(t.clone());
+                    tokens.push(t);
+                }
+                None => {
+                    if generated.is_empty() {
+                        self.warn_if_untrained()?;
+                    }
+                    return Ok((generated, StopReason::NoCandidates));
+                }
+            }
+        }
+        Ok((generated, StopReason::MaxLength))
+    }
+
+    /// Like [`generate_string`](Self::generate_string), also returning why generation stopped.
+    pub fn generate_with_stop_reason(
+        &mut self,
+        input: &str,
+        context: usize,
+        max_len: usize,
+    ) -> Result<(String, StopReason)> {
+        let tokens = self.seed_tokens(input);
+        let previous = tokens.iter().filter(|t| **t != Token::Bos).count();
+        let (generated, reason) =
+            self.generate_tokens(tokens, context, max_len, &Sampling::Weighted)?;
+        Ok((self.render_tokens(&generated, previous), reason))
+    }
+
+    /// Generate up to `max_len` tokens continuing raw `input`, as bytes.
+    ///
+    /// The counterpart of [`note_bytes`](Self::note_bytes): in
+    /// [`TokenMode::Byte`] every generated token is one output byte; tokens of
+    /// the other modes are appended as UTF-8. An empty `input` starts from the
+    /// `Token::Bos` context.
+    pub fn generate_bytes(
+        &mut self,
+        input: &[u8],
+        context: usize,
+        max_len: usize,
+    ) -> Result<Vec<u8>> {
+        let tokens = Self::seed_or_bos(self.tokenize_bytes(input));
+        let previous = tokens.iter().filter(|t| **t != Token::Bos).count();
+        let (generated, _) = self.generate_tokens(tokens, context, max_len, &Sampling::Weighted)?;
+        let mut out = vec![];
+        for (i, t) in generated.iter().enumerate() {
+            match t {
+                Token::Byte(b) => out.push(*b),
+                t => {
+                    let mut text = String::new();
+                    self.render_token(t, previous + i, &mut text);
+                    out.extend(text.into_bytes());
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Sample the next character for the longest known suffix of `input`.
+    ///
+    /// Returns `None` when nothing is known or the sampled token is not a character.
+    pub fn predict_all_string_return(
+        &mut self,
+        input: &str,
+        context: usize,
+    ) -> Result<Option<char>> {
+        let tokens = self.tokenize(input);
+        match self.predict_sampled(&tokens, context, &Sampling::Weighted)? {
+            Some(Token::C(c)) => Ok(Some(c)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Generate up to `max_len` tokens continuing `input`.
+    ///
+    /// Only the generated text is returned, not `input` itself. An empty `input`
+    /// starts from the sequence-initial tokens learned with
+    /// [`set_bos`](Self::set_bos). Generation
+    /// stops early when `Token::Eos` is sampled or no continuation is known
+    /// for the current content, unless a [fallback](Self::set_fallback) is set.
+    pub fn generate_string(
+        &mut self,
+        input: &str,
+        context: usize,
+        max_len: usize,
+    ) -> Result<String> {
+        self.generate_sampled(input, context, max_len, &Sampling::Weighted)
+    }
+
+    /// Generate until `Token::Eos` is sampled, nothing more is known, or `max_len`
+    /// tokens were produced.
+    ///
+    /// Train with [`set_eos_mode`](Self::set_eos_mode) so that the model learns
+    /// where sequences end.
+    pub fn generate_until_eos(
+        &mut self,
+        input: &str,
+        context: usize,
+        max_len: usize,
+    ) -> Result<String> {
+        self.generate_string(input, context, max_len)
+    }
+
+    /// Predict up to `n` tokens following `input`, feeding each one back as context.
+    ///
+    /// Stops early when nothing more can be predicted and returns what was produced.
+    pub fn predict_next_n(&mut self, input: &str, context: usize, n: usize) -> Result<String> {
+        self.generate_string(input, context, n)
+    }
+
+    /// The most likely continuation of `input`, one highest-count token at a time.
+    ///
+    /// Ties are settled as set with [`set_tie_break`](Self::set_tie_break), by
+    /// default in favor of the candidate seen first in training, so for a given
+    /// model the result is always the same and no random numbers are drawn
+    /// (unless [`TieBreak::Random`] or [`Fallback::RandomSeen`] is set). Stops
+    /// at `Token::Eos`, when nothing follows, or after `max_len` tokens.
+    pub fn complete_greedy(
+        &mut self,
+        input: &str,
+        context: usize,
+        max_len: usize,
+    ) -> Result<String> {
+        self.generate_sampled(input, context, max_len, &Sampling::Greedy)
+    }
+
+    /// Like [`generate_string`](Self::generate_string), but sampling with the given temperature.
+    ///
+    /// Below 1.0 the distribution is sharpened toward frequent tokens, above 1.0 it is
+    /// flattened, and 0.0 deterministically picks the most frequent token every step.
+    pub fn generate_string_with_temperature(
+        &mut self,
+        input: &str,
+        context: usize,
+        temperature: f64,
+        max_len: usize,
+    ) -> Result<String> {
+        self.generate_sampled(input, context, max_len, &Sampling::Temperature(temperature))
+    }
+
+    /// Like [`generate_string`](Self::generate_string), but only sampling among the
+    /// `k` most frequent candidates at each step.
+    pub fn generate_string_top_k(
+        &mut self,
+        input: &str,
+        context: usize,
+        k: usize,
+        max_len: usize,
+    ) -> Result<String> {
+        self.generate_sampled(input, context, max_len, &Sampling::TopK(k))
+    }
+
+    /// Like [`generate_string`](Self::generate_string), but with nucleus sampling:
+    /// only the most frequent candidates covering probability `p` are considered.
+    pub fn generate_string_top_p(
+        &mut self,
+        input: &str,
+        context: usize,
+        p: f64,
+        max_len: usize,
+    ) -> Result<String> {
+        self.generate_sampled(input, context, max_len, &Sampling::TopP(p))
+    }
+
+    /// Sample the next token after the longest known suffix of `input`, favoring
+    /// rare continuations.
+    ///
+    /// Each candidate is weighted by `max_count - count + 1` instead of its
+    /// count, so the least frequent continuation seen in training is the most
+    /// likely pick and the most frequent one the least. Meant for stress-testing
+    /// the sampler and for surprising output; returns `None` when nothing is known.
+    pub fn predict_antifreq(&mut self, input: &str, context: usize) -> Result<Option<Token>> {
+        let tokens = self.tokenize(input);
+        self.predict_sampled(&tokens, context, &Sampling::Inverse)
+    }
+
+    /// Like [`generate_string`](Self::generate_string), but sampling inversely to
+    /// frequency as in [`predict_antifreq`](Self::predict_antifreq).
+    pub fn generate_string_inverse(
+        &mut self,
+        input: &str,
+        context: usize,
+        max_len: usize,
+    ) -> Result<String> {
+        self.generate_sampled(input, context, max_len, &Sampling::Inverse)
+    }
+
+    /// Like [`generate_string`](Self::generate_string), but sampling from the
+    /// [backoff distribution](Self::backoff_candidates) with the given `discount`.
+    pub fn generate_string_backoff(
+        &mut self,
+        input: &str,
+        context: usize,
+        discount: f64,
+        max_len: usize,
+    ) -> Result<String> {
+        self.generate_sampled(input, context, max_len, &Sampling::Backoff(discount))
+    }
+
+    /// Like [`generate_string`](Self::generate_string), but discouraging loops:
+    /// candidates among the last [`DEFAULT_REPEAT_WINDOW`] tokens have their
+    /// weight divided by `penalty`.
+    ///
+    /// A penalty of 1.0 changes nothing; larger values make repeats rarer.
+    pub fn generate_string_with_penalty(
+        &mut self,
+        input: &str,
+        context: usize,
+        penalty: f64,
+        max_len: usize,
+    ) -> Result<String> {
+        self.generate_sampled(input, context, max_len, &Sampling::Penalty(penalty))
+    }
+
+    /// Print `input` followed by generated text until no prediction is available.
+    ///
+    /// Stops at a cycle as well, after [`DEFAULT_LOOP_LIMIT`] repeats unless
+    /// another [loop limit](Self::set_loop_limit) is set.
+    pub fn generate(&mut self, input: &str, context: usize) -> Result<()> {
+        let out = self.generate_string(input, context, usize::MAX)?;
+        println!("{}{}\n\n", input, out);
+        Ok(())
+    }
+
+    /// Like [`generate`](Self::generate), but with nucleus sampling over probability `p`.
+    pub fn generate_top_p(&mut self, input: &str, context: usize, p: f64) -> Result<()> {
+        let out = self.generate_string_top_p(input, context, p, usize::MAX)?;
+        println!("{}{}\n\n", input, out);
+        Ok(())
+    }
+
+    /// Like [`generate`](Self::generate), but with a repeat `penalty` as in
+    /// [`generate_string_with_penalty`](Self::generate_string_with_penalty).
+    pub fn generate_with_penalty(
+        &mut self,
+        input: &str,
+        context: usize,
+        penalty: f64,
+    ) -> Result<()> {
+        let out = self.generate_string_with_penalty(input, context, penalty, usize::MAX)?;
+        println!("{}{}\n\n", input, out);
+        Ok(())
+    }
+
+    /// Like [`generate`](Self::generate), but sampling with the given temperature.
+    pub fn generate_with_temperature(
+        &mut self,
+        input: &str,
+        context: usize,
+        temperature: f64,
+    ) -> Result<()> {
+        let out = self.generate_string_with_temperature(input, context, temperature, usize::MAX)?;
+        println!("{}{}\n\n", input, out);
+        Ok(())
+    }
+}
+
+impl TokenStash {
+    /// Persist everything and close the model, reporting failures that dropping it would only log.
+    pub fn close(mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+impl Drop for TokenStash {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("failed to flush cached contexts: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(c: char, count: u64) -> TokenEntry {
+        TokenEntry {
+            value: Token::C(c),
+            count,
+        }
+    }
+
+    #[test]
+    fn top_p_keeps_smallest_covering_set() {
+        let entries = vec![entry('a', 1), entry('b', 6), entry('c', 3)];
+        let kept: Vec<_> = top_p(entries.clone(), 0.8)
+            .into_iter()
+            .map(|e| e.value)
+            .collect();
+        assert_eq!(kept, vec![Token::C('b'), Token::C('c')]);
+        assert_eq!(top_p(entries, 0.5).len(), 1);
+    }
+
+    fn temp_stash() -> (tempfile::TempDir, TokenStash) {
+        let dir = tempfile::tempdir().unwrap();
+        let stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        (dir, stash)
+    }
+
+    #[test]
+    fn note_string_ignores_short_input() {
+        let (_dir, mut stash) = temp_stash();
+        stash.note_string("").unwrap();
+        stash.note_string("a").unwrap();
+        assert!(stash.predict_token("").unwrap().is_empty());
+        assert!(stash.predict_token("a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn cached_updates_survive_eviction_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        {
+            let mut stash = TokenStash::new(prefix).unwrap();
+            stash.set_cache_capacity(1);
+            stash.note_string("ab").unwrap();
+            stash.note_string("cd").unwrap();
+            stash.note_string("ab").unwrap();
+            assert_eq!(stash.predict_token("a").unwrap()[0].count, 2);
+        }
+        let stash = TokenStash::new(prefix).unwrap();
+        assert_eq!(stash.predict_token("a").unwrap()[0].count, 2);
+        assert_eq!(stash.predict_token("c").unwrap()[0].count, 1);
+    }
+
+    #[test]
+    fn batched_training_accumulates_within_one_call() {
+        let (_dir, mut stash) = temp_stash();
+        stash.note_text_batched("ababab", 2).unwrap();
+        let after_a = stash.predict_token("a").unwrap();
+        assert_eq!(after_a.len(), 1);
+        assert_eq!(after_a[0].value, Token::C('b'));
+        assert_eq!(after_a[0].count, 3);
+    }
+
+    #[test]
+    fn candidates_are_sorted_by_count() {
+        let (_dir, mut stash) = temp_stash();
+        stash.note_string("ta").unwrap();
+        stash.note_string("th").unwrap();
+        stash.note_string("th").unwrap();
+        stash.note_string("ti").unwrap();
+        let cand = stash.candidates("t").unwrap();
+        let values: Vec<_> = cand.iter().map(|e| (e.value.clone(), e.count)).collect();
+        assert_eq!(
+            values,
+            vec![(Token::C('h'), 2), (Token::C('a'), 1), (Token::C('i'), 1)]
+        );
+    }
+
+    #[test]
+    fn word_mode_predicts_and_generates_words() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash =
+            TokenStash::with_mode(dir.path().to_str().unwrap(), TokenMode::Word).unwrap();
+        stash.note_text("the quick brown fox jumps", 3).unwrap();
+        let cand = stash.predict_token("the").unwrap();
+        assert_eq!(cand[0].value, Token::Word("quick".to_string()));
+        assert_eq!(
+            stash.generate_string("the", 3, 10).unwrap(),
+            " quick brown fox jumps"
+        );
+    }
+
+    #[test]
+    fn numbers_are_rendered_back_in_decimal() {
+        let (_dir, mut stash) = temp_stash();
+        stash.note_text("x 42 y", 4).unwrap();
+        assert_eq!(stash.generate_string("x ", 4, 2).unwrap(), "42 ");
+    }
+
+    #[test]
+    fn seeded_generation_is_reproducible() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        TokenStash::new(prefix)
+            .unwrap()
+            .note_text("abacadaeafagahaiajakalamanaoapaq", 2)
+            .unwrap();
+        let generate = || {
+            TokenStash::with_seed(prefix, 7)
+                .unwrap()
+                .generate_string("a", 2, 50)
+                .unwrap()
+        };
+        let first = generate();
+        let second = generate();
+        assert_eq!(first, second);
+        assert!(!first.is_empty());
+    }
+
+    #[test]
+    fn opening_an_unusable_path_is_an_error() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let prefix = file.path().to_str().unwrap();
+        assert!(TokenStash::new(prefix).is_err());
+    }
+
+    #[test]
+    fn top_k_is_clamped_to_candidate_count() {
+        let (_dir, mut stash) = temp_stash();
+        stash.note_text("tatbtbtc.", 1).unwrap();
+        let greedy = stash.predict_token_top_k("t", 1).unwrap();
+        assert_eq!(greedy.len(), 1);
+        assert_eq!(greedy[0].value, Token::C('b'));
+        assert_eq!(stash.predict_token_top_k("t", 10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn predict_next_n_stops_at_n_or_when_unknown() {
+        let (_dir, mut stash) = temp_stash();
+        stash.note_text("abcabcabc", 2).unwrap();
+        assert_eq!(stash.predict_next_n("ab", 2, 4).unwrap(), "cabc");
+        assert_eq!(stash.predict_next_n("x", 2, 4).unwrap(), "");
+    }
+
+    #[test]
+    fn generation_stops_at_learned_eos() {
+        let (_dir, mut stash) = temp_stash();
+        stash.set_eos_mode(EosMode::Line);
+        stash.note_text("ab\nab\n", 3).unwrap();
+        assert_eq!(stash.generate_until_eos("a", 3, 100).unwrap(), "b\n");
+    }
+
+    fn snapshot(stash: &mut TokenStash) -> HashMap<String, Vec<TokenEntry>> {
+        let mut all = HashMap::new();
+        stash
+            .for_each_context(|hash, _, hits| {
+                all.insert(hash.to_string(), hits.entries);
+                Ok(())
+            })
+            .unwrap();
+        all
+    }
+
+    #[test]
+    fn single_pass_training_matches_per_prefix_training() {
+        let text = "the cat sat on the mat, the end";
+        let (_old_dir, mut old) = temp_stash();
+        for i in 2..=text.len() {
+            old.note_all_string(&text[0..i], 4).unwrap();
+        }
+        let (_new_dir, mut new) = temp_stash();
+        new.note_text(text, 4).unwrap();
+        assert_eq!(snapshot(&mut old), snapshot(&mut new));
+    }
+
+    #[test]
+    fn streamed_training_matches_whole_text_training() {
+        let text = "héllo wörld 1234\nwörld 56 héllo\nno newline";
+        let (_whole_dir, mut whole) = temp_stash();
+        whole.note_text(text, 4).unwrap();
+        let (_stream_dir, mut streamed) = temp_stash();
+        streamed.set_cache_capacity(2);
+        streamed.note_reader(std::io::Cursor::new(text), 4).unwrap();
+        assert_eq!(snapshot(&mut whole), snapshot(&mut streamed));
+    }
+
+    #[test]
+    fn non_ascii_text_is_trained_and_predicted_by_character() {
+        let text = "héllo wörld";
+        let (_old_dir, mut old) = temp_stash();
+        for (i, c) in text.char_indices().skip(1) {
+            old.note_all_string(&text[..i + c.len_utf8()], 4).unwrap();
+        }
+        old.predict_all_string("wö", 4).unwrap();
+        assert_eq!(old.predict_token("h").unwrap()[0].value, Token::C('é'));
+        assert_eq!(old.predict_token("wö").unwrap()[0].value, Token::C('r'));
+        let (_new_dir, mut new) = temp_stash();
+        new.note_text(text, 4).unwrap();
+        assert_eq!(snapshot(&mut old), snapshot(&mut new));
+    }
+
+    #[test]
+    fn in_memory_stash_needs_no_directory() {
+        let mut stash = TokenStash::in_memory();
+        assert_eq!(stash.prefix(), "");
+        stash.note_text("abab", 2).unwrap();
+        stash.flush().unwrap();
+        assert_eq!(stash.predict_token("ab").unwrap()[0].value, Token::C('a'));
+        assert_eq!(stash.stats().unwrap().contexts, 4);
+    }
+
+    #[test]
+    fn backoff_merges_orders_with_a_discount() {
+        let mut stash = TokenStash::in_memory();
+        // "a" -> b (2), c (1); "xa" -> c (1)
+        stash.note_text("abab xac", 2).unwrap();
+        let merged = stash
+            .backoff_candidates("xa", 2, DEFAULT_BACKOFF_DISCOUNT)
+            .unwrap();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].0, Token::C('c'));
+        let b = DEFAULT_BACKOFF_DISCOUNT * 2.0 / 3.0;
+        assert!((merged[1].1 - b / (1.0 + b)).abs() < 1e-9);
+        assert!((merged[0].1 + merged[1].1 - 1.0).abs() < 1e-9);
+        assert!(stash.backoff_candidates("q", 2, 0.4).unwrap().is_empty());
+        assert!(!stash
+            .generate_string_backoff("xa", 2, 0.4, 3)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn normalized_stash_shares_statistics_across_case() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_normalization(Normalization::Lowercase);
+        stash.note_text("Ab ab AB", 1).unwrap();
+        let after_a = stash.predict_token("A").unwrap();
+        assert_eq!(after_a, vec![entry('b', 3)]);
+    }
+
+    #[test]
+    fn store_options_are_applied_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        let options = StoreOptions {
+            cache_size: Some(64 * 1024),
+            bloom_bits_per_key: Some(10),
+            repair: true,
+        };
+        {
+            let mut stash = TokenStash::with_options(prefix, TokenMode::Char, &options).unwrap();
+            stash.note_text("abc", 2).unwrap();
+        }
+        let stash = TokenStash::with_options(prefix, TokenMode::Char, &options).unwrap();
+        assert_eq!(stash.predict_token("ab").unwrap(), vec![entry('c', 1)]);
+    }
+
+    #[test]
+    fn is_trained_sees_cached_and_stored_updates() {
+        let (dir, mut stash) = temp_stash();
+        assert!(!stash.is_trained().unwrap());
+        assert_eq!(stash.generate_string("a", 4, 10).unwrap(), "");
+        stash.note_string("ab").unwrap();
+        assert!(stash.is_trained().unwrap());
+        drop(stash);
+        let stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        assert!(stash.is_trained().unwrap());
+    }
+
+    #[test]
+    fn empty_seed_generates_from_sequence_starts() {
+        let mut stash = TokenStash::in_memory();
+        assert_eq!(stash.generate_string("", 4, 10).unwrap(), "");
+        stash.set_bos(true);
+        stash.set_eos_mode(EosMode::Line);
+        stash.note_text("xa\nxa\n", 4).unwrap();
+        assert_eq!(stash.generate_string("", 4, 10).unwrap(), "xa\n");
+        // The start marker is only a context, never a continuation.
+        assert!(snapshot(&mut stash)
+            .values()
+            .flatten()
+            .all(|e| e.value != Token::Bos));
+    }
+
+    #[test]
+    fn streamed_training_records_markers_like_whole_text_training() {
+        let text = "ab\ncd\n";
+        for eos_mode in [EosMode::None, EosMode::Line, EosMode::Document] {
+            let mut whole = TokenStash::in_memory();
+            let mut streamed = TokenStash::in_memory();
+            for stash in [&mut whole, &mut streamed] {
+                stash.set_bos(true);
+                stash.set_eos_mode(eos_mode);
+            }
+            whole.note_text(text, 3).unwrap();
+            streamed.note_reader(text.as_bytes(), 3).unwrap();
+            assert_eq!(snapshot(&mut whole), snapshot(&mut streamed));
+        }
+    }
+
+    #[test]
+    fn line_boundaries_keep_lines_independent() {
+        let text = "ab\ncd\n";
+        let mut stash = TokenStash::in_memory();
+        stash.set_line_boundaries(true);
+        stash.note_text(text, 3).unwrap();
+        assert_eq!(stash.predict_token("ab").unwrap().len(), 1);
+        assert!(stash.predict_token("\n").unwrap().is_empty());
+        assert!(stash.predict_token("b\nc").unwrap().is_empty());
+        // Without start markers the first token of a line is never a continuation.
+        assert_eq!(stash.unigram_counts().unwrap().len(), 3);
+
+        for eos_mode in [EosMode::None, EosMode::Document] {
+            let mut whole = TokenStash::in_memory();
+            let mut streamed = TokenStash::in_memory();
+            for stash in [&mut whole, &mut streamed] {
+                stash.set_line_boundaries(true);
+                stash.set_bos(true);
+                stash.set_eos_mode(eos_mode);
+            }
+            whole.note_text(text, 3).unwrap();
+            streamed.note_reader(text.as_bytes(), 3).unwrap();
+            assert_eq!(snapshot(&mut whole), snapshot(&mut streamed));
+        }
+    }
+
+    #[test]
+    fn fallback_keeps_generation_going() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_bos(true);
+        stash.note_text("ab", 1).unwrap();
+        assert_eq!(stash.generate_string("a", 1, 5).unwrap(), "b");
+        stash.set_fallback(Fallback::Restart);
+        assert_eq!(stash.generate_string("a", 1, 5).unwrap(), "babab");
+
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("ab", 1).unwrap();
+        stash.set_fallback(Fallback::RandomSeen);
+        // Only "b" was ever seen as a continuation.
+        assert_eq!(stash.generate_string("a", 1, 3).unwrap(), "bbb");
+    }
+
+    #[test]
+    fn unigram_counts_cover_every_training_path() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abb", 2).unwrap();
+        stash.note_string("xb").unwrap();
+        stash.note_all_string("xc", 2).unwrap();
+        assert_eq!(
+            stash.unigram_counts().unwrap(),
+            vec![entry('b', 3), entry('c', 1)]
+        );
+    }
+
+    #[test]
+    fn repeat_penalty_discourages_recent_tokens() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_seed(3);
+        // After "a", "a" is nine times as likely as "b", which ends generation.
+        stash.note_text("aaaaaaaaaab", 1).unwrap();
+        assert!(stash.generate_string("a", 1, 200).unwrap().len() > 1);
+        stash.set_seed(3);
+        let penalized = stash
+            .generate_string_with_penalty("a", 1, 1000.0, 200)
+            .unwrap();
+        assert_eq!(penalized, "b");
+    }
+
+    #[test]
+    fn byte_mode_models_binary_input() {
+        let mut stash = TokenStash::with_store(Box::new(MemoryStore::default()), TokenMode::Byte);
+        stash.note_bytes(&[0x00, 0xff, 0x01, 0xfe], 2).unwrap();
+        assert_eq!(
+            stash.generate_bytes(&[0x00], 2, 10).unwrap(),
+            vec![0xff, 0x01, 0xfe]
+        );
+        // Multi-byte characters are learned byte by byte but rendered whole.
+        stash.note_bytes("xé!".as_bytes(), 2).unwrap();
+        assert_eq!(stash.generate_string("x", 2, 10).unwrap(), "é!");
+    }
+
+    #[test]
+    fn generation_over_trivial_model_returns_a_character() {
+        let (_dir, mut stash) = temp_stash();
+        stash.note_text("ab", 1).unwrap();
+        assert_eq!(stash.predict_all_string_return("a", 1).unwrap(), Some('b'));
+    }
+
+    #[test]
+    fn colliding_keys_keep_separate_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        {
+            let mut stash = TokenStash::new(prefix).unwrap();
+            stash.set_key_strategy(KeyStrategy::Constant);
+            stash.note_string("ab").unwrap();
+            stash.note_string("cd").unwrap();
+            stash.note_text("ab", 1).unwrap();
+        }
+        let mut stash = TokenStash::new(prefix).unwrap();
+        stash.set_key_strategy(KeyStrategy::Constant);
+        let after_a = stash.predict_token("a").unwrap();
+        assert_eq!(
+            after_a,
+            vec![TokenEntry {
+                value: Token::C('b'),
+                count: 2
+            }]
+        );
+        let after_c = stash.predict_token("c").unwrap();
+        assert_eq!(
+            after_c,
+            vec![TokenEntry {
+                value: Token::C('d'),
+                count: 1
+            }]
+        );
+        assert!(stash.predict_token("x").unwrap().is_empty());
+    }
+
+    #[test]
+    fn namespaces_isolate_models_in_one_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        TokenStash::new_namespaced(prefix, "en")
+            .unwrap()
+            .note_text("ab", 1)
+            .unwrap();
+        let de = TokenStash::new_namespaced(prefix, "de").unwrap();
+        assert!(!de.is_trained().unwrap());
+        drop(de);
+        let en = TokenStash::new_namespaced(prefix, "en").unwrap();
+        assert_eq!(en.predict_token("a").unwrap().len(), 1);
+        drop(en);
+
+        let shared = SharedStore::new(Box::new(MemoryStore::default()));
+        let mut a = TokenStash::with_store(Box::new(shared.namespace("a")), TokenMode::Char);
+        let mut b = TokenStash::with_store(Box::new(shared.namespace("b")), TokenMode::Char);
+        a.note_text("xy", 1).unwrap();
+        b.note_text("xz", 1).unwrap();
+        assert_eq!(a.predict_token("x").unwrap()[0].value, Token::C('y'));
+        assert_eq!(b.predict_token("x").unwrap()[0].value, Token::C('z'));
+        b.clear().unwrap();
+        assert!(a.is_trained().unwrap());
+    }
+
+    #[test]
+    fn root_model_leaves_namespaces_in_its_database_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        // A one-letter namespace's keys share the "1:" prefix with root bigram contexts.
+        for namespace in ["en", "x"] {
+            TokenStash::new_namespaced(prefix, namespace)
+                .unwrap()
+                .note_text("abac", 1)
+                .unwrap();
+        }
+        let mut root = TokenStash::new(prefix).unwrap();
+        root.note_text("xyxy", 1).unwrap();
+        assert_eq!(root.stats().unwrap().contexts, 2);
+        assert_eq!(root.contexts().unwrap().count(), 3);
+        let bigrams = root.ngram_report(2, 10).unwrap();
+        assert!(bigrams.iter().all(|(ngram, _)| ngram[0] != Token::C('a')));
+        assert_eq!(
+            root.size_report()
+                .unwrap()
+                .iter()
+                .map(|s| s.contexts)
+                .sum::<usize>(),
+            3
+        );
+        let mut exported = vec![];
+        root.export_json(&mut exported).unwrap();
+        assert!(!String::from_utf8(exported).unwrap().contains("\"a\""));
+        assert!(root.nearest_context("a", 0).unwrap().is_none());
+        root.prune(2).unwrap();
+        root.decay_all(0.0).unwrap();
+        root.clear().unwrap();
+        drop(root);
+
+        for namespace in ["en", "x"] {
+            let stash = TokenStash::new_namespaced(prefix, namespace).unwrap();
+            let candidates = stash.candidates("a").unwrap();
+            assert_eq!(candidates.len(), 2);
+            assert!(candidates.iter().all(|e| e.count == 1));
+        }
+    }
+
+    #[test]
+    fn predictions_can_run_from_several_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        stash.note_text("abcabd", 2).unwrap();
+        let stash = std::sync::Arc::new(stash);
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let stash = stash.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        assert_eq!(stash.candidates("ab").unwrap().len(), 2);
+                        assert_eq!(stash.backoff_candidates("xb", 2, 0.4).unwrap().len(), 2);
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn tokenize_and_hash_tokens_are_usable_on_their_own() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_normalization(Normalization::Lowercase);
+        let tokens = stash.tokenize("Ab 12");
+        assert_eq!(
+            tokens,
+            vec![Token::C('a'), Token::C('b'), Token::C(' '), Token::Num(12)]
+        );
+        assert_eq!(
+            stash.hash_tokens(&tokens),
+            format!("4:{}", KeyStrategy::Sha256.key(&tokens))
+        );
+        stash.note_text("Ab 12", 4).unwrap();
+        let mut keys = vec![];
+        stash
+            .for_each_context(|hash, _, _| {
+                keys.push(hash.to_string());
+                Ok(())
+            })
+            .unwrap();
+        assert!(keys.contains(&stash.hash_tokens(&tokens[..3])));
+    }
+
+    #[test]
+    fn progress_is_reported_periodically_and_at_the_end() {
+        let mut stash = TokenStash::in_memory();
+        let text = "ab".repeat(PROGRESS_INTERVAL + 10);
+        let mut reports = vec![];
+        stash
+            .note_text_with_progress(&text, 2, |processed, total| {
+                reports.push((processed, total))
+            })
+            .unwrap();
+        let total = text.len();
+        assert_eq!(
+            reports,
+            vec![
+                (PROGRESS_INTERVAL, total),
+                (2 * PROGRESS_INTERVAL, total),
+                (total, total)
+            ]
+        );
+    }
+
+    #[test]
+    fn min_context_refuses_short_contexts() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("xabc", 2).unwrap();
+        assert_eq!(stash.generate_string("zb", 2, 5).unwrap(), "c");
+        stash.set_min_context(2);
+        assert_eq!(stash.generate_string("zb", 2, 5).unwrap(), "");
+        assert_eq!(stash.predict_all_string_return("zb", 2).unwrap(), None);
+        assert!(stash.backoff_candidates("zb", 2, 0.4).unwrap().is_empty());
+        assert_eq!(stash.predict_all_string_return("ab", 2).unwrap(), Some('c'));
+    }
+
+    #[test]
+    fn predicted_probabilities_are_normalized() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abacab", 1).unwrap();
+        let probs = stash.predict_token_probs("a").unwrap();
+        assert_eq!(probs.len(), 2);
+        assert_eq!(probs[0].0, Token::C('b'));
+        assert!((probs[0].1 - 2.0 / 3.0).abs() < 1e-9);
+        assert!((probs.iter().map(|(_, p)| p).sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(stash.predict_token_probs("z").unwrap().is_empty());
+    }
+
+    #[test]
+    fn generation_reports_why_it_stopped() {
+        let mut stash = TokenStash::in_memory();
+        // "x" -> a always; "a" -> b (2), c (1)
+        stash.note_text("xab xab xac", 1).unwrap();
+        assert_eq!(
+            stash.generate_with_stop_reason("x", 1, 1).unwrap(),
+            ("a".to_string(), StopReason::MaxLength)
+        );
+        assert_eq!(
+            stash.generate_with_stop_reason("z", 1, 5).unwrap(),
+            (String::new(), StopReason::NoCandidates)
+        );
+        stash.set_confidence_threshold(0.9);
+        assert_eq!(
+            stash.generate_with_stop_reason("x", 1, 10).unwrap(),
+            ("a".to_string(), StopReason::LowConfidence)
+        );
+        let mut state = stash.gen_state("x", 1);
+        assert_eq!(stash.step(&mut state).unwrap(), Some('a'));
+        assert_eq!(stash.step(&mut state).unwrap(), None);
+
+        let mut stash = TokenStash::in_memory();
+        stash.set_eos_mode(EosMode::Document);
+        stash.note_text("ab", 1).unwrap();
+        assert_eq!(
+            stash.generate_with_stop_reason("a", 1, 10).unwrap(),
+            ("b".to_string(), StopReason::Eos)
+        );
+    }
+
+    #[test]
+    fn pre_tokenized_input_bypasses_the_tokenizer() {
+        let mut stash = TokenStash::in_memory();
+        let words =
+            |s: &str| -> Vec<Token> { s.split('-').map(|w| Token::Word(w.to_string())).collect() };
+        stash.note_tokens(&words("new-york-city"), 2).unwrap();
+        assert_eq!(
+            stash.predict_from_tokens(&words("new-york")).unwrap(),
+            vec![TokenEntry {
+                value: Token::Word("city".to_string()),
+                count: 1
+            }]
+        );
+        assert_eq!(stash.predict_from_tokens(&words("york")).unwrap().len(), 1);
+        assert!(stash
+            .predict_from_tokens(&words("city"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn predict_trace_reports_the_context_used() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abc xbd", 2).unwrap();
+        let trace = stash.predict_trace("ab", 2).unwrap();
+        assert_eq!(trace.context_len, Some(2));
+        assert_eq!(trace.chosen, Some(Token::C('c')));
+        // "zb" is unknown, so it backs off to "b", seen before "c" and "d".
+        let trace = stash.predict_trace("zb", 2).unwrap();
+        assert_eq!(trace.context_len, Some(1));
+        assert_eq!(trace.candidates.len(), 2);
+        assert!(trace
+            .candidates
+            .iter()
+            .any(|e| Some(&e.value) == trace.chosen.as_ref()));
+        assert_eq!(
+            stash.predict_trace("q", 2).unwrap(),
+            PredictTrace {
+                context_len: None,
+                candidates: vec![],
+                chosen: None
+            }
+        );
+    }
+
+    #[test]
+    fn flush_policy_bounds_the_buffered_updates() {
+        let context = [Token::C('a')];
+        let persisted = |stash: &TokenStash| -> bool {
+            stash
+                .store
+                .get(&stash.hash_tokens(&context))
+                .unwrap()
+                .is_some()
+        };
+
+        let mut stash = TokenStash::in_memory();
+        stash.note_next_token(&context, &Token::C('b')).unwrap();
+        assert!(!persisted(&stash));
+
+        let mut stash = TokenStash::in_memory();
+        stash.set_flush_policy(FlushPolicy::WriteThrough);
+        stash.note_next_token(&context, &Token::C('b')).unwrap();
+        assert!(persisted(&stash));
+
+        let mut stash = TokenStash::in_memory();
+        stash.set_flush_policy(FlushPolicy::EveryUpdates(2));
+        stash.note_next_token(&context, &Token::C('b')).unwrap();
+        assert!(!persisted(&stash));
+        stash.note_next_token(&context, &Token::C('c')).unwrap();
+        assert!(persisted(&stash));
+
+        let mut stash = TokenStash::in_memory();
+        stash.set_flush_policy(FlushPolicy::Interval(Duration::ZERO));
+        stash.note_next_token(&context, &Token::C('b')).unwrap();
+        assert!(persisted(&stash));
+    }
+
+    #[test]
+    fn close_persists_cached_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        let mut stash = TokenStash::new(prefix).unwrap();
+        stash.note_string("ab").unwrap();
+        stash.close().unwrap();
+        let stash = TokenStash::new(prefix).unwrap();
+        assert_eq!(stash.predict_token("a").unwrap()[0].value, Token::C('b'));
+    }
+
+    #[test]
+    fn contexts_iterates_lazily_and_reports_bad_values() {
+        let mut store = MemoryStore::default();
+        store.put("0:broken", vec![0xff, 0x01, 0x07]).unwrap();
+        let mut stash = TokenStash::with_store(Box::new(store), TokenMode::Char);
+        stash.note_text("abc", 1).unwrap();
+        let mut contexts = vec![];
+        let mut errors = 0;
+        for item in stash.contexts().unwrap() {
+            match item {
+                Ok((_, context, hits)) => contexts.push((context, hits.entries.len())),
+                Err(_) => errors += 1,
+            }
+        }
+        assert_eq!(errors, 1);
+        assert_eq!(contexts.len(), 3);
+        assert!(contexts.contains(&(vec![Token::C('a')], 1)));
+        assert!(stash.contexts().unwrap().take(1).count() == 1);
+    }
+
+    /// Splits on hyphens, e.g. into syllables.
+    struct HyphenTokenizer;
+
+    impl Tokenizer for HyphenTokenizer {
+        fn tokenize(&self, src: &str) -> Vec<Token> {
+            src.split('-').map(|s| Token::Word(s.to_string())).collect()
+        }
+
+        fn detokenize(&self, tokens: &[Token]) -> String {
+            let parts: Vec<&str> = tokens
+                .iter()
+                .filter_map(|t| match t {
+                    Token::Word(w) => Some(w.as_str()),
+                    _ => None,
+                })
+                .collect();
+            parts.join("-")
+        }
+
+        fn detokenize_continuation(&self, tokens: &[Token], previous: usize) -> String {
+            let text = self.detokenize(tokens);
+            if previous > 0 && !text.is_empty() {
+                format!("-{}", text)
+            } else {
+                text
+            }
+        }
+    }
+
+    #[test]
+    fn custom_tokenizers_drive_training_and_generation() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_tokenizer(Box::new(HyphenTokenizer));
+        stash.note_text("ba-na-na", 1).unwrap();
+        assert_eq!(
+            stash.predict_token("ba").unwrap()[0].value,
+            Token::Word("na".to_string())
+        );
+        assert_eq!(stash.generate_string("ba", 1, 1).unwrap(), "-na");
+    }
+
+    #[test]
+    fn two_pass_training_never_stores_rare_transitions() {
+        let text = "ab ab ab ac";
+        let mut two_pass = TokenStash::in_memory();
+        two_pass.note_text("ab", 1).unwrap();
+        two_pass.train_two_pass(text, 1, 2).unwrap();
+        // "a" -> b (3 in the text, plus 1 already stored); "a" -> c was too rare.
+        assert_eq!(
+            two_pass.candidates("a").unwrap(),
+            vec![TokenEntry {
+                value: Token::C('b'),
+                count: 4
+            }]
+        );
+        assert!(two_pass.candidates("c").unwrap().is_empty());
+
+        let mut pruned = TokenStash::in_memory();
+        pruned.note_text(text, 1).unwrap();
+        pruned.prune(2).unwrap();
+        let mut expected = snapshot(&mut pruned);
+        let mut got = TokenStash::in_memory();
+        got.train_two_pass(text, 1, 2).unwrap();
+        let got = snapshot(&mut got);
+        expected.retain(|_, entries| !entries.is_empty());
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn greedy_completion_is_deterministic() {
+        let mut stash = TokenStash::in_memory();
+        // "a" -> b twice; "b" -> c, d once each, so the tie goes to c.
+        stash.note_text("abcabd", 1).unwrap();
+        for seed in 0..5 {
+            stash.set_seed(seed);
+            assert_eq!(stash.complete_greedy("a", 1, 3).unwrap(), "bca");
+        }
+        assert_eq!(stash.complete_greedy("z", 1, 3).unwrap(), "");
+        assert_eq!(stash.complete_greedy("a", 1, 0).unwrap(), "");
+    }
+
+    #[test]
+    fn tie_break_decides_between_equally_frequent_candidates() {
+        let mut stash = TokenStash::in_memory();
+        // "a" -> z, b once each.
+        stash.note_text("az.ab.", 1).unwrap();
+        assert_eq!(stash.complete_greedy("a", 1, 1).unwrap(), "z");
+        stash.set_tie_break(TieBreak::ByToken);
+        assert_eq!(stash.complete_greedy("a", 1, 1).unwrap(), "b");
+        assert_eq!(
+            stash
+                .generate_string_with_temperature("a", 1, 0.0, 1)
+                .unwrap(),
+            "b"
+        );
+        stash.set_tie_break(TieBreak::Random);
+        stash.set_seed(1);
+        let picks: HashSet<String> = (0..50)
+            .map(|_| stash.complete_greedy("a", 1, 1).unwrap())
+            .collect();
+        assert_eq!(picks.len(), 2);
+    }
+
+    #[test]
+    fn indexed_counting_survives_direct_changes() {
+        let context = [Token::C('a')];
+        let mut stored = StoredHits::default();
+        stored.add_count(&context, &Token::C('x'), 1);
+        stored.add_count(&context, &Token::C('y'), 1);
+        // Direct access drops the index, so it is rebuilt from what is left.
+        stored.get_or_insert(&context).entries.remove(0);
+        stored.add_count(&context, &Token::C('y'), 2);
+        stored.add_count(&context, &Token::C('x'), 4);
+        assert_eq!(
+            stored.get(&context).unwrap().entries,
+            vec![entry('y', 3), entry('x', 4)]
+        );
+    }
+
+    #[test]
+    fn max_branching_keeps_the_most_frequent_continuations() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_max_branching(Some(2));
+        stash.note_text("abacacadad", 1).unwrap();
+        stash.flush().unwrap();
+        assert_eq!(
+            stash.predict_token("a").unwrap(),
+            vec![entry('c', 2), entry('d', 2)]
+        );
+        assert_eq!(stash.unigram_counts().unwrap().len(), 4);
+        stash
+            .note_next_token(&[Token::C('a')], &Token::C('e'))
+            .unwrap();
+        stash.flush().unwrap();
+        assert_eq!(
+            stash.predict_token("a").unwrap(),
+            vec![entry('c', 2), entry('d', 2)]
+        );
+    }
+
+    #[test]
+    fn loop_limit_stops_cycling_generation() {
+        let mut stash = TokenStash::in_memory();
+        // "ab" and "ba" only ever continue the cycle.
+        stash.note_text("abab", 2).unwrap();
+        stash.set_loop_limit(Some(3));
+        assert_eq!(
+            stash.generate_with_stop_reason("ab", 2, 100).unwrap(),
+            ("ababab".to_string(), StopReason::Repeating)
+        );
+        stash.set_loop_limit(None);
+        assert_eq!(
+            stash.generate_with_stop_reason("ab", 2, 100).unwrap().1,
+            StopReason::MaxLength
+        );
+    }
+
+    #[test]
+    fn unbounded_generation_stops_at_a_cycle_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        stash.note_text("abab", 2).unwrap();
+        stash.generate("ab", 2).unwrap();
+        let (out, reason) = stash
+            .generate_with_stop_reason("ab", 2, usize::MAX)
+            .unwrap();
+        assert_eq!(reason, StopReason::Repeating);
+        assert_eq!(out.len(), 2 * DEFAULT_LOOP_LIMIT);
+    }
+
+    #[test]
+    fn explicit_max_len_is_honoured_by_default() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abab", 2).unwrap();
+        let (out, reason) = stash.generate_with_stop_reason("ab", 2, 500).unwrap();
+        assert_eq!(out.len(), 500);
+        assert_eq!(reason, StopReason::MaxLength);
+    }
+
+    #[test]
+    fn document_markers_bound_generation_from_scratch() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_document_markers(true);
+        stash.note_text("hi\nyo", 8).unwrap();
+        assert_eq!(
+            stash.generate_with_stop_reason("", 8, 100).unwrap(),
+            ("hi\nyo".to_string(), StopReason::Eos)
+        );
+        stash.set_document_markers(false);
+        stash.note_text("hi", 8).unwrap();
+        assert_eq!(stash.generate_string("", 8, 100).unwrap(), "hi\nyo");
+    }
+
+    #[test]
+    fn preprocessor_applies_to_training_and_prediction() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_preprocessor(Some(Box::new(crate::collapse_whitespace)));
+        stash.note_text("a   b", 2).unwrap();
+        assert_eq!(stash.predict_token("a \t ").unwrap(), vec![entry('b', 1)]);
+        stash.set_preprocessor(None);
+        assert!(stash.predict_token("a  ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn antifreq_sampling_favors_rare_continuations() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_seed(5);
+        // "a" -> b (9), c (1): inverted weights are b 1, c 9.
+        stash.note_text("abababababababababac", 1).unwrap();
+        let rare = (0..200)
+            .filter(|_| stash.predict_antifreq("a", 1).unwrap() == Some(Token::C('c')))
+            .count();
+        assert!(rare > 150, "{}", rare);
+        assert_eq!(stash.predict_antifreq("z", 1).unwrap(), None);
+        assert_eq!(
+            inverted(vec![entry('b', 9), entry('c', 1)]),
+            vec![entry('b', 1), entry('c', 9)]
+        );
+        assert_eq!(
+            inverted(vec![entry('b', u64::MAX), entry('c', 0), entry('d', 1)]),
+            vec![entry('b', 1), entry('c', u64::MAX), entry('d', u64::MAX)]
+        );
+    }
+
+    #[test]
+    fn batch_prediction_matches_single_lookups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        stash.note_text("the cat then the hat", 3).unwrap();
+        stash.flush().unwrap();
+        // One context cached, the others read from the database.
+        stash
+            .note_next_token(&stash.tokenize("ca"), &Token::C('r'))
+            .unwrap();
+        let inputs = ["th", "ca", "zz", "", "th"];
+        let batch = stash.predict_batch(&inputs).unwrap();
+        let single: Vec<_> = inputs
+            .iter()
+            .map(|input| stash.predict_token(input).unwrap())
+            .collect();
+        assert_eq!(batch, single);
+        assert_eq!(batch[1], vec![entry('t', 1), entry('r', 1)]);
+        assert!(stash.predict_batch(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reader_progress_counts_lines() {
+        let mut stash = TokenStash::in_memory();
+        let text = "ab\n".repeat(2 * PROGRESS_INTERVAL + 5);
+        let mut reports = vec![];
+        stash
+            .note_reader_with_progress(text.as_bytes(), 2, |lines| reports.push(lines))
+            .unwrap();
+        let interval = PROGRESS_INTERVAL as u64;
+        assert_eq!(reports, vec![interval, 2 * interval, 2 * interval + 5]);
+    }
+}
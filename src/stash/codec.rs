@@ -0,0 +1,322 @@
+//! The compact on-disk format of [`StoredHits`].
+//!
+//! A value starts with [`MARKER`] and a format version. Version 1 then holds
+//! the number of buckets and, per bucket, the context tokens and the entries,
+//! with every length and count written as LEB128. ASCII characters take a
+//! single byte; every other token starts with a tag byte above 0x7f.
+//!
+//! Values without the marker were written by earlier releases as plain bincode
+//! (whose varint encoding never starts with 0xff) and are still decoded. The
+//! very first releases stored a bare `TokenHits` without its context; such
+//! values are recognized, but can only be reported as needing a retrain.
+
+use bincode::error::DecodeError;
+
+use super::{ContextHits, StoredHits};
+use crate::error::{HashmemError, Result};
+use crate::token::{Token, TokenEntry, TokenHits};
+
+const MARKER: u8 = 0xff;
+const VERSION: u8 = 1;
+
+const TAG_CHAR: u8 = 0x80;
+const TAG_NUM: u8 = 0x81;
+const TAG_WORD: u8 = 0x82;
+const TAG_EOS: u8 = 0x83;
+const TAG_BOS: u8 = 0x84;
+const TAG_BYTE: u8 = 0x85;
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn put_token(out: &mut Vec<u8>, token: &Token) {
+    match token {
+        Token::C(c) if c.is_ascii() => out.push(*c as u8),
+        Token::C(c) => {
+            out.push(TAG_CHAR);
+            put_varint(out, *c as u64);
+        }
+        Token::Num(n) => {
+            out.push(TAG_NUM);
+            put_varint(out, *n);
+        }
+        Token::Word(w) => {
+            out.push(TAG_WORD);
+            put_varint(out, w.len() as u64);
+            out.extend_from_slice(w.as_bytes());
+        }
+        Token::Eos => out.push(TAG_EOS),
+        Token::Bos => out.push(TAG_BOS),
+        Token::Byte(b) => {
+            out.push(TAG_BYTE);
+            out.push(*b);
+        }
+    }
+}
+
+/// `hits` in the current compact format.
+pub(super) fn encode(hits: &StoredHits) -> Vec<u8> {
+    let mut out = vec![MARKER, VERSION];
+    put_varint(&mut out, hits.buckets.len() as u64);
+    for bucket in &hits.buckets {
+        put_varint(&mut out, bucket.context.len() as u64);
+        for t in &bucket.context {
+            put_token(&mut out, t);
+        }
+        put_varint(&mut out, bucket.hits.entries.len() as u64);
+        for e in &bucket.hits.entries {
+            put_token(&mut out, &e.value);
+            put_varint(&mut out, e.count);
+        }
+    }
+    out
+}
+
+fn invalid(what: &str) -> DecodeError {
+    DecodeError::OtherString(format!("invalid stored hits: {}", what))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> std::result::Result<u8, DecodeError> {
+        let (first, rest) = self
+            .data
+            .split_first()
+            .ok_or(DecodeError::UnexpectedEnd { additional: 1 })?;
+        self.data = rest;
+        Ok(*first)
+    }
+
+    fn varint(&mut self) -> std::result::Result<u64, DecodeError> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            n |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    fn len(&mut self) -> std::result::Result<usize, DecodeError> {
+        let n = self.varint()?;
+        // Every element takes at least one byte, which bounds allocations.
+        if n > self.data.len() as u64 {
+            return Err(DecodeError::UnexpectedEnd {
+                additional: (n - self.data.len() as u64) as usize,
+            });
+        }
+        Ok(n as usize)
+    }
+
+    fn token(&mut self) -> std::result::Result<Token, DecodeError> {
+        Ok(match self.byte()? {
+            b @ 0..=0x7f => Token::C(b as char),
+            TAG_CHAR => {
+                let c = u32::try_from(self.varint()?).map_err(|_| invalid("char"))?;
+                Token::C(char::from_u32(c).ok_or_else(|| invalid("char"))?)
+            }
+            TAG_NUM => Token::Num(self.varint()?),
+            TAG_WORD => {
+                let len = self.len()?;
+                let (word, rest) = self.data.split_at(len);
+                self.data = rest;
+                Token::Word(String::from_utf8(word.to_vec()).map_err(|_| invalid("word"))?)
+            }
+            TAG_EOS => Token::Eos,
+            TAG_BOS => Token::Bos,
+            TAG_BYTE => Token::Byte(self.byte()?),
+            _ => return Err(invalid("token tag")),
+        })
+    }
+}
+
+/// Decode a value in any format this release can read.
+pub(super) fn decode(data: &[u8]) -> Result<StoredHits> {
+    match data {
+        [MARKER, VERSION, rest @ ..] => Ok(decode_v1(rest)?),
+        [MARKER, version, ..] => Err(invalid(&format!(
+            "format version {} is newer than this release reads ({})",
+            version, VERSION
+        ))
+        .into()),
+        [MARKER] => Err(invalid("missing format version").into()),
+        _ => match bincode::decode_from_slice::<StoredHits, _>(data, bincode::config::standard()) {
+            Ok((hits, read)) if read == data.len() => Ok(hits),
+            _ if is_baseline(data) => Err(HashmemError::RetrainRequired(
+                "it stores counts without their contexts; delete it and train the model again"
+                    .to_string(),
+            )),
+            Ok(_) => Err(invalid("trailing bytes").into()),
+            Err(e) => Err(e.into()),
+        },
+    }
+}
+
+/// Whether `data` is exactly a bare `TokenHits`, as the first releases stored.
+fn is_baseline(data: &[u8]) -> bool {
+    bincode::decode_from_slice::<TokenHits, _>(data, bincode::config::standard())
+        .is_ok_and(|(_, read)| read == data.len())
+}
+
+fn decode_v1(data: &[u8]) -> std::result::Result<StoredHits, DecodeError> {
+    let mut r = Reader { data };
+    let mut buckets = Vec::with_capacity(r.len()?);
+    for _ in 0..buckets.capacity() {
+        let mut context = Vec::with_capacity(r.len()?);
+        for _ in 0..context.capacity() {
+            context.push(r.token()?);
+        }
+        let mut entries = Vec::with_capacity(r.len()?);
+        for _ in 0..entries.capacity() {
+            let value = r.token()?;
+            let count = r.varint()?;
+            entries.push(TokenEntry { value, count });
+        }
+        buckets.push(ContextHits::new(context, TokenHits { entries }));
+    }
+    if !r.data.is_empty() {
+        return Err(invalid("trailing bytes"));
+    }
+    Ok(StoredHits { buckets })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> StoredHits {
+        let entries = vec![
+            TokenEntry {
+                value: Token::C('a'),
+                count: 3,
+            },
+            TokenEntry {
+                value: Token::C('é'),
+                count: 300,
+            },
+            TokenEntry {
+                value: Token::Num(u64::MAX),
+                count: u64::MAX,
+            },
+            TokenEntry {
+                value: Token::Word("wörd".to_string()),
+                count: 1,
+            },
+            TokenEntry {
+                value: Token::Eos,
+                count: 1,
+            },
+            TokenEntry {
+                value: Token::Byte(0xff),
+                count: 1,
+            },
+        ];
+        StoredHits {
+            buckets: vec![ContextHits::new(
+                vec![Token::Bos, Token::C('x'), Token::C('日')],
+                TokenHits { entries },
+            )],
+        }
+    }
+
+    #[test]
+    fn compact_format_round_trips() {
+        let hits = sample();
+        let encoded = encode(&hits);
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(decoded.buckets[0].context, hits.buckets[0].context);
+        assert_eq!(
+            decoded.buckets[0].hits.entries,
+            hits.buckets[0].hits.entries
+        );
+        assert!(decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn typical_text_contexts_encode_smaller_than_bincode() {
+        let hits = StoredHits {
+            buckets: vec![ContextHits::new(
+                "the quick".chars().map(Token::C).collect(),
+                TokenHits {
+                    entries: " bf"
+                        .chars()
+                        .map(|c| TokenEntry {
+                            value: Token::C(c),
+                            count: 12,
+                        })
+                        .collect(),
+                },
+            )],
+        };
+        let legacy = bincode::encode_to_vec(&hits, bincode::config::standard()).unwrap();
+        assert!(encode(&hits).len() < legacy.len());
+    }
+
+    #[test]
+    fn legacy_bincode_values_are_still_read() {
+        let hits = sample();
+        let legacy = bincode::encode_to_vec(&hits, bincode::config::standard()).unwrap();
+        let decoded = decode(&legacy).unwrap();
+        assert_eq!(
+            decoded.buckets[0].hits.entries,
+            hits.buckets[0].hits.entries
+        );
+    }
+
+    #[test]
+    fn values_from_newer_releases_are_rejected_by_version() {
+        let mut future = encode(&sample());
+        future[1] = VERSION + 1;
+        let err = decode(&future).err().unwrap().to_string();
+        assert!(err.contains("format version 2"), "{}", err);
+        assert!(decode(&[MARKER]).is_err());
+    }
+
+    #[test]
+    fn version_one_values_stay_readable() {
+        // Written by version 1: "ab" followed by "c" 3 times and the end 200 times.
+        let v1 = [MARKER, 1, 1, 2, b'a', b'b', 2, b'c', 3, TAG_EOS, 0xc8, 0x01];
+        let decoded = decode(&v1).unwrap();
+        assert_eq!(
+            decoded.buckets[0].context,
+            vec![Token::C('a'), Token::C('b')]
+        );
+        assert_eq!(
+            decoded.buckets[0].hits.entries,
+            vec![
+                TokenEntry {
+                    value: Token::C('c'),
+                    count: 3
+                },
+                TokenEntry {
+                    value: Token::Eos,
+                    count: 200
+                },
+            ]
+        );
+    }
+
+    /// A value of the first releases: `b` twice and the number 7 once, as a bare `TokenHits`.
+    const BASELINE_VALUE: [u8; 7] = [2, 0, b'b', 2, 1, 7, 1];
+
+    #[test]
+    fn values_without_contexts_ask_for_a_retrain() {
+        assert!(matches!(
+            decode(&BASELINE_VALUE),
+            Err(HashmemError::RetrainRequired(_))
+        ));
+        let mut legacy = bincode::encode_to_vec(sample(), bincode::config::standard()).unwrap();
+        legacy.push(0);
+        assert!(matches!(decode(&legacy), Err(HashmemError::Decode(_))));
+    }
+}
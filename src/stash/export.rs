@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use super::{add_count, StoredHits, TokenStash};
+use crate::error::Result;
+use crate::token::{Token, TokenEntry, TokenHits};
+
+/// One context in the JSON export: its key, the context itself and the observed continuations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ExportedContext {
+    hash: String,
+    context: Vec<Token>,
+    entries: Vec<TokenEntry>,
+}
+
+/// What [`TokenStash::import_json`] does with a context that already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Replace the stored continuations with the imported ones.
+    Overwrite,
+    /// Add the imported counts to the stored ones.
+    Sum,
+}
+
+impl TokenStash {
+    /// Write the whole model to `writer` as a JSON array of `{hash, context, entries}` objects.
+    ///
+    /// Contexts are streamed one at a time, so the model never has to fit in memory.
+    pub fn export_json(&mut self, mut writer: impl Write) -> Result<()> {
+        writer.write_all(b"[")?;
+        let mut first = true;
+        self.for_each_context(|hash, context, hits: TokenHits| {
+            if !first {
+                writer.write_all(b",")?;
+            }
+            first = false;
+            let exported = ExportedContext {
+                hash: hash.to_string(),
+                context: context.to_vec(),
+                entries: hits.entries,
+            };
+            serde_json::to_writer(&mut writer, &exported)?;
+            Ok(())
+        })?;
+        writer.write_all(b"]")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load contexts from JSON produced by [`export_json`](Self::export_json).
+    ///
+    /// Contexts not yet in the model are inserted as they are; existing ones are
+    /// replaced or summed according to `mode`. Everything is written at once.
+    pub fn import_json(&mut self, reader: impl Read, mode: ImportMode) -> Result<()> {
+        let imported: Vec<ExportedContext> = serde_json::from_reader(reader)?;
+        self.flush()?;
+        let mut batch: HashMap<String, StoredHits> = HashMap::new();
+        for context in &imported {
+            let stored = match batch.entry(context.hash.clone()) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    let stored = self.read_hits_from_file(e.key())?;
+                    e.insert(stored)
+                }
+            };
+            let hits = stored.get_or_insert(&context.context);
+            if mode == ImportMode::Overwrite {
+                hits.entries.clear();
+            }
+            for e in &context.entries {
+                add_count(hits, &e.value, e.count);
+            }
+        }
+        self.write_batch(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_lists_every_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        stash.note_text("abc", 2).unwrap();
+        let mut out = vec![];
+        stash.export_json(&mut out).unwrap();
+        let exported: Vec<ExportedContext> = serde_json::from_slice(&out).unwrap();
+        // "a" -> b, "b" -> c, "ab" -> c, and the overall frequencies b, c
+        assert_eq!(exported.len(), 4);
+        let total: u64 = exported
+            .iter()
+            .flat_map(|c| c.entries.iter())
+            .filter(|e| e.value == Token::C('c'))
+            .map(|e| e.count)
+            .sum();
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn import_overwrites_or_sums() {
+        let src = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(src.path().to_str().unwrap()).unwrap();
+        stash.note_text("ab", 1).unwrap();
+        let mut json = vec![];
+        stash.export_json(&mut json).unwrap();
+
+        let dst = tempfile::tempdir().unwrap();
+        let mut other = TokenStash::new(dst.path().to_str().unwrap()).unwrap();
+        other.note_text("ab", 1).unwrap();
+        other.import_json(&json[..], ImportMode::Sum).unwrap();
+        assert_eq!(other.predict_token("a").unwrap()[0].count, 2);
+        other.import_json(&json[..], ImportMode::Overwrite).unwrap();
+        assert_eq!(other.predict_token("a").unwrap()[0].count, 1);
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+
+use super::{LoopGuard, Sampling, TokenStash};
+use crate::error::Result;
+use crate::token::Token;
+
+/// The state of an incremental generation, advanced one character at a time
+/// by [`TokenStash::step`].
+///
+/// Only the last `context` tokens are kept, so a long generation never
+/// rebuilds or re-tokenizes the text produced so far.
+#[derive(Debug, Clone)]
+pub struct GenState {
+    tokens: Vec<Token>,
+    context: usize,
+    pending: VecDeque<char>,
+    rendered: usize,
+    done: bool,
+    guard: LoopGuard,
+}
+
+impl GenState {
+    /// The tokens currently used as context for the next prediction.
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// Whether generation has stopped, either at `Token::Eos` or at an unknown context.
+    pub fn is_done(&self) -> bool {
+        self.done && self.pending.is_empty()
+    }
+
+    fn push(&mut self, token: Token) {
+        self.tokens.push(token);
+        if self.tokens.len() > self.context {
+            let excess = self.tokens.len() - self.context;
+            self.tokens.drain(..excess);
+        }
+    }
+}
+
+impl TokenStash {
+    /// Start an incremental generation continuing `input`, using up to `context` tokens.
+    pub fn gen_state(&self, input: &str, context: usize) -> GenState {
+        let mut state = GenState {
+            tokens: vec![],
+            context,
+            pending: VecDeque::new(),
+            rendered: 0,
+            done: false,
+            guard: LoopGuard::default(),
+        };
+        for t in self.seed_tokens(input) {
+            state.push(t);
+        }
+        state.rendered = state.tokens.iter().filter(|t| **t != Token::Bos).count();
+        state
+    }
+
+    /// Generate the next character of `state`.
+    ///
+    /// Tokens that render to several characters (numbers, words) are returned one
+    /// character per call. Returns `None` once `Token::Eos` is sampled, no
+    /// continuation is known (and the [fallback](Self::set_fallback) gives none
+    /// either) or the [loop limit](Self::set_loop_limit) is exceeded, and on
+    /// every call after that.
+    pub fn step(&mut self, state: &mut GenState) -> Result<Option<char>> {
+        if let Some(c) = state.pending.pop_front() {
+            return Ok(Some(c));
+        }
+        if state.done {
+            return Ok(None);
+        }
+        let sampled =
+            self.predict_with_fallback(&mut state.tokens, state.context, &Sampling::Weighted)?;
+        match sampled {
+            Some(Token::Eos) | None => {
+                state.done = true;
+                Ok(None)
+            }
+            Some(t) if self.below_confidence(&state.tokens, state.context, &t)? => {
+                state.done = true;
+                Ok(None)
+            }
+            Some(t)
+                if state.guard.repeated(
+                    &state.tokens,
+                    state.context,
+                    &t,
+                    self.loop_limit_for(usize::MAX),
+                ) =>
+            {
+                state.done = true;
+                Ok(None)
+            }
+            Some(t) => {
+                let mut rendered = String::new();
+                self.render_token(&t, state.rendered, &mut rendered);
+                state.rendered += 1;
+                state.pending.extend(rendered.chars());
+                state.push(t);
+                Ok(state.pending.pop_front())
+            }
+        }
+    }
+}
+
+impl TokenStash {
+    /// Set the characters after which [`generate_sentence`](Self::generate_sentence) stops.
+    pub fn set_sentence_ends(&mut self, ends: &[char]) {
+        self.sentence_ends = ends.to_vec();
+    }
+
+    /// Generate one sentence continuing `input`, up to and including the first
+    /// sentence-ending character, but no more than `max_len` characters.
+    ///
+    /// The ending characters are [`DEFAULT_SENTENCE_ENDS`](crate::DEFAULT_SENTENCE_ENDS)
+    /// unless changed with [`set_sentence_ends`](Self::set_sentence_ends).
+    /// Generation also ends where [`step`](Self::step) does, so the sentence
+    /// may be left unfinished at `Token::Eos`, an unknown context or a loop.
+    pub fn generate_sentence(
+        &mut self,
+        input: &str,
+        context: usize,
+        max_len: usize,
+    ) -> Result<String> {
+        let mut state = self.gen_state(input, context);
+        let mut out = String::new();
+        for _ in 0..max_len {
+            match self.step(&mut state)? {
+                Some(c) => {
+                    out.push(c);
+                    if self.sentence_ends.contains(&c) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stepping_matches_string_generation() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        stash.note_text("hello 42 world", 3).unwrap();
+        let expected = stash.generate_string("hel", 3, 100).unwrap();
+        assert_eq!(expected, "lo 42 world");
+
+        let mut state = stash.gen_state("hel", 3);
+        let mut out = String::new();
+        while let Some(c) = stash.step(&mut state).unwrap() {
+            out.push(c);
+            assert!(state.tokens().len() <= 3);
+        }
+        assert_eq!(out, expected);
+        assert!(state.is_done());
+        assert_eq!(stash.step(&mut state).unwrap(), None);
+    }
+
+    #[test]
+    fn sentences_end_at_the_first_stop_character() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("one. two! three?", 4).unwrap();
+        assert_eq!(stash.generate_sentence("on", 4, 100).unwrap(), "e.");
+        assert_eq!(stash.generate_sentence("tw", 4, 100).unwrap(), "o!");
+        stash.set_sentence_ends(&[';']);
+        assert_eq!(stash.generate_sentence("tw", 4, 100).unwrap(), "o! three?");
+    }
+
+    #[test]
+    fn stepping_stops_at_the_loop_limit() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abab", 2).unwrap();
+        stash.set_loop_limit(Some(3));
+        assert_eq!(stash.generate_sentence("ab", 2, 100).unwrap(), "ababab");
+    }
+
+    #[test]
+    fn sentences_stop_at_max_len() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abab", 2).unwrap();
+        assert_eq!(stash.generate_sentence("ab", 2, 5).unwrap(), "ababa");
+    }
+}
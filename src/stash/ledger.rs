@@ -0,0 +1,94 @@
+use sha256::digest;
+
+use super::{TokenStash, RESERVED_PREFIX};
+use crate::error::Result;
+
+/// The reserved key recording that the document with `digest` was trained.
+fn ledger_key(digest: &str) -> String {
+    format!("{}doc:{}", RESERVED_PREFIX, digest)
+}
+
+impl TokenStash {
+    /// Train on `input` like [`note_text`](Self::note_text) unless the same text was already trained this way.
+    ///
+    /// The SHA-256 of every document trained through this method is kept in the
+    /// database, so running it twice on the same file does not double the
+    /// counts. Returns whether training happened. Only the text is compared:
+    /// a document trained again with another context length is still skipped.
+    pub fn note_text_once(&mut self, input: &str, context: usize) -> Result<bool> {
+        let key = ledger_key(&digest(input));
+        if self.store.get(&key)?.is_some() {
+            info!("document {} was already trained, skipping", &key);
+            return Ok(false);
+        }
+        // The ledger entry is written together with the counts, so a failed
+        // write cannot leave the document trained but unrecorded.
+        let sequences = self.training_sequences(input);
+        let batch = self.count_sequences(&sequences, context, &mut |_, _| {})?;
+        self.write_batch_with(batch, vec![(key, Some(vec![]))])?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{MemoryStore, Store};
+    use crate::token::Token;
+    use crate::TokenMode;
+    use std::sync::{Arc, Mutex};
+
+    /// A [`MemoryStore`] remembering the keys of every batch it applies.
+    struct RecordingStore {
+        inner: MemoryStore,
+        batches: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl Store for RecordingStore {
+        fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.inner.get(key)
+        }
+
+        fn apply(&mut self, changes: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+            let keys = changes.iter().map(|(key, _)| key.clone()).collect();
+            self.batches.lock().unwrap().push(keys);
+            self.inner.apply(changes)
+        }
+
+        fn for_each(&self, f: &mut dyn FnMut(&str, &[u8]) -> Result<()>) -> Result<()> {
+            self.inner.for_each(f)
+        }
+    }
+
+    #[test]
+    fn documents_are_only_trained_once() {
+        let mut stash = TokenStash::in_memory();
+        assert!(stash.note_text_once("abab", 1).unwrap());
+        assert!(!stash.note_text_once("abab", 1).unwrap());
+        assert_eq!(stash.candidates("a").unwrap()[0].count, 2);
+        assert!(stash.note_text_once("ab", 1).unwrap());
+        assert_eq!(stash.candidates("a").unwrap()[0].count, 3);
+        // The ledger is not mistaken for a context.
+        assert_eq!(stash.stats().unwrap().contexts, 2);
+        assert_eq!(stash.prune(1).unwrap(), 0);
+        assert!(!stash.note_text_once("abab", 1).unwrap());
+        assert_eq!(stash.unigram_counts().unwrap()[0].value, Token::C('b'));
+    }
+
+    #[test]
+    fn ledger_entry_is_written_with_the_counts() {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let store = RecordingStore {
+            inner: MemoryStore::default(),
+            batches: batches.clone(),
+        };
+        let mut stash = TokenStash::with_store(Box::new(store), TokenMode::Char);
+        assert!(stash.note_text_once("abab", 1).unwrap());
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].contains(&ledger_key(&digest("abab"))));
+        assert!(batches[0]
+            .iter()
+            .any(|key| !key.starts_with(RESERVED_PREFIX)));
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use super::{merge_hits, StoredHits, TokenStash, RESERVED_PREFIX};
+use crate::error::Result;
+use crate::token::TokenHits;
+
+impl TokenStash {
+    /// Apply `f` to the hits of every stored context, writing the results at once.
+    ///
+    /// Entries left with a count of zero are dropped, and contexts left without
+    /// entries are deleted.
+    fn rewrite_hits<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut TokenHits),
+    {
+        self.flush()?;
+        let mut stored = vec![];
+        self.for_each_own_key(&mut |key, value| {
+            if key.starts_with(RESERVED_PREFIX) {
+                return Ok(());
+            }
+            stored.push((key.to_string(), Self::decode_hits(value)?));
+            Ok(())
+        })?;
+        let mut changes = vec![];
+        for (key, mut hits) in stored {
+            for bucket in &mut hits.buckets {
+                f(&mut bucket.hits);
+                bucket.hits.entries.retain(|e| e.count > 0);
+            }
+            hits.buckets.retain(|b| !b.hits.entries.is_empty());
+            if hits.buckets.is_empty() {
+                changes.push((key, None));
+            } else {
+                changes.push((key, Some(Self::encode_hits(&hits)?)));
+            }
+        }
+        self.apply_changes(changes)?;
+        self.cache.hits_by_hash.clear();
+        Ok(())
+    }
+
+    /// Multiply every count by `factor`, rounding down, so older observations fade.
+    ///
+    /// Entries that reach zero are removed, as are contexts left without entries.
+    /// Results are clamped to the representable range, so a negative or NaN factor
+    /// forgets everything and a factor above one saturates instead of overflowing.
+    pub fn decay_all(&mut self, factor: f64) -> Result<()> {
+        self.rewrite_hits(|hits| {
+            for e in &mut hits.entries {
+                // Float to integer casts saturate, and NaN becomes zero.
+                e.count = (e.count as f64 * factor).floor() as u64;
+            }
+        })
+    }
+
+    /// Drop every entry seen fewer than `min_count` times and delete contexts left empty.
+    ///
+    /// Returns the number of entries removed.
+    pub fn prune(&mut self, min_count: u64) -> Result<usize> {
+        let mut removed = 0;
+        self.rewrite_hits(|hits| {
+            for e in &mut hits.entries {
+                if e.count < min_count {
+                    e.count = 0;
+                    removed += 1;
+                }
+            }
+        })?;
+        Ok(removed)
+    }
+
+    /// Delete every stored context, including unflushed updates, leaving an empty model.
+    ///
+    /// Namespaced models sharing the database are kept.
+    pub fn clear(&mut self) -> Result<()> {
+        let mut changes = vec![];
+        self.for_each_own_key(&mut |key, _| {
+            changes.push((key.to_string(), None));
+            Ok(())
+        })?;
+        self.apply_changes(changes)?;
+        self.cache.hits_by_hash.clear();
+        self.cache.dirty.clear();
+        self.format_marked = false;
+        Ok(())
+    }
+
+    /// Delete everything learned about what follows exactly `context`.
+    ///
+    /// Returns whether the context was known. Other contexts sharing its key are
+    /// kept; the key itself is removed once nothing else is stored under it.
+    pub fn forget_context(&mut self, context: &str) -> Result<bool> {
+        let tokens = self.tokenize(context);
+        let hash = self.hash_tokens(&tokens);
+        let hits = self.cached_hits(&hash)?;
+        let before = hits.buckets.len();
+        hits.buckets.retain(|b| b.context != tokens);
+        if hits.buckets.len() == before {
+            return Ok(false);
+        }
+        let value = if hits.buckets.is_empty() {
+            None
+        } else {
+            Some(Self::encode_hits(hits)?)
+        };
+        self.apply_changes(vec![(hash.clone(), value)])?;
+        self.cache.dirty.remove(&hash);
+        Ok(true)
+    }
+
+    /// Add every count stored under `other_prefix` into this model, writing them at once.
+    ///
+    /// Contexts are re-keyed with this model's key strategy, so the other model
+    /// may have been trained with a different one. The other model must not be
+    /// open elsewhere.
+    pub fn merge_from(&mut self, other_prefix: &str) -> Result<()> {
+        if !Path::new(other_prefix).join("db").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no database under {}", other_prefix),
+            )
+            .into());
+        }
+        let mut other = TokenStash::new(other_prefix)?;
+        self.flush()?;
+        let mut batch: HashMap<String, StoredHits> = HashMap::new();
+        other.for_each_context(|_, context, hits| {
+            let stored = match batch.entry(self.hash_tokens(context)) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    let stored = self.read_hits_from_file(e.key())?;
+                    e.insert(stored)
+                }
+            };
+            merge_hits(stored.get_or_insert(context), &hits);
+            Ok(())
+        })?;
+        self.write_batch(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::{Token, TokenEntry};
+
+    #[test]
+    fn decay_halves_counts_and_drops_empty_contexts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        stash.note_text("aaab", 1).unwrap();
+        stash.decay_all(0.5).unwrap();
+        assert_eq!(
+            stash.predict_token("a").unwrap(),
+            vec![TokenEntry {
+                value: Token::C('a'),
+                count: 1
+            }]
+        );
+        stash.decay_all(0.5).unwrap();
+        assert!(stash.predict_token("a").unwrap().is_empty());
+        assert_eq!(stash.stats().unwrap().contexts, 0);
+    }
+
+    #[test]
+    fn prune_removes_rare_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        // "a" -> a (2), b (1); "b" -> c (1); overall a (2), b (1), c (1)
+        stash.note_text("aaabc", 1).unwrap();
+        assert_eq!(stash.prune(2).unwrap(), 4);
+        assert_eq!(stash.predict_token("a").unwrap().len(), 1);
+        assert!(stash.predict_token("b").unwrap().is_empty());
+        assert_eq!(stash.stats().unwrap().contexts, 1);
+        assert_eq!(stash.prune(2).unwrap(), 0);
+    }
+
+    #[test]
+    fn clear_leaves_an_empty_model() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abc", 2).unwrap();
+        stash.note_string("ab").unwrap();
+        stash.clear().unwrap();
+        assert!(!stash.is_trained().unwrap());
+        assert!(stash.predict_token("a").unwrap().is_empty());
+        stash.note_text("ab", 1).unwrap();
+        assert_eq!(stash.predict_token("a").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn forget_context_removes_only_that_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        let mut stash = TokenStash::new(prefix).unwrap();
+        stash.note_text("abac", 1).unwrap();
+        // Not yet flushed when it is forgotten.
+        stash.note_string("ax").unwrap();
+        assert!(stash.forget_context("a").unwrap());
+        assert!(!stash.forget_context("a").unwrap());
+        assert!(!stash.forget_context("zz").unwrap());
+        drop(stash);
+
+        let stash = TokenStash::new(prefix).unwrap();
+        assert!(stash.predict_token("a").unwrap().is_empty());
+        assert_eq!(stash.predict_token("b").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn merge_sums_matching_entries_and_adds_new_ones() {
+        let shard = tempfile::tempdir().unwrap();
+        let shard_prefix = shard.path().to_str().unwrap();
+        TokenStash::new(shard_prefix)
+            .unwrap()
+            .note_text("abac", 1)
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        stash.note_text("ab", 1).unwrap();
+        stash.merge_from(shard_prefix).unwrap();
+        let after_a = stash.candidates("a").unwrap();
+        assert_eq!(after_a[0].value, Token::C('b'));
+        assert_eq!(after_a[0].count, 2);
+        assert_eq!(after_a[1].value, Token::C('c'));
+        assert_eq!(after_a[1].count, 1);
+        assert_eq!(stash.predict_token("b").unwrap().len(), 1);
+
+        assert!(stash.merge_from("/nonexistent/hashmem").is_err());
+    }
+
+    #[test]
+    fn merged_counts_saturate_instead_of_wrapping() {
+        let shard = tempfile::tempdir().unwrap();
+        let shard_prefix = shard.path().to_str().unwrap();
+        {
+            let mut shard = TokenStash::new(shard_prefix).unwrap();
+            shard.note_text("ab", 1).unwrap();
+            shard
+                .rewrite_hits(|hits| hits.entries[0].count = u64::MAX - 1)
+                .unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        stash.note_text("abab", 1).unwrap();
+        stash.merge_from(shard_prefix).unwrap();
+        assert_eq!(stash.candidates("a").unwrap()[0].count, u64::MAX);
+        stash.note_string("ab").unwrap();
+        assert_eq!(stash.candidates("a").unwrap()[0].count, u64::MAX);
+
+        // A second continuation next to the saturated one.
+        stash.note_string("ac").unwrap();
+        let probs = stash.predict_token_probs("a").unwrap();
+        assert_eq!(probs.len(), 2);
+        assert!((probs.iter().map(|(_, p)| p).sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(stash.context_entropy("a").unwrap() >= 0.0);
+        assert!(stash.perplexity("abac", 1).unwrap().is_finite());
+        stash.set_seed(1);
+        for _ in 0..20 {
+            assert!(stash.predict_all_string_return("a", 1).unwrap().is_some());
+        }
+        assert_eq!(stash.generate_string_top_p("a", 1, 0.5, 1).unwrap(), "b");
+        assert_eq!(stash.stats().unwrap().observations, u64::MAX);
+    }
+}
@@ -0,0 +1,92 @@
+use super::TokenStash;
+use crate::error::Result;
+use crate::token::{Token, TokenHits};
+
+/// The number of token insertions, deletions and substitutions turning `a` into `b`.
+fn edit_distance(a: &[Token], b: &[Token]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ta) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, tb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ta != tb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+impl TokenStash {
+    /// The stored context closest to `context` by token edit distance, with its hits.
+    ///
+    /// Only contexts at most `max_distance` edits away are considered; among
+    /// equally close ones the first in key order wins. Every stored context is
+    /// compared, so this is meant for small models and for debugging why an
+    /// input could not be predicted. The contexts are read from the database
+    /// once and kept in memory until the model is next written to, so repeated
+    /// queries do not decode it again. The overall token frequencies are not a
+    /// context and are never returned.
+    pub fn nearest_context(
+        &mut self,
+        context: &str,
+        max_distance: usize,
+    ) -> Result<Option<(String, TokenHits)>> {
+        let query = self.tokenize(context);
+        // Flushing first drops a stale list along with the pending updates.
+        self.flush()?;
+        if self.context_list.is_none() {
+            let mut list = vec![];
+            self.for_each_context(|_, stored, hits| {
+                if !stored.is_empty() {
+                    list.push((stored.to_vec(), hits));
+                }
+                Ok(())
+            })?;
+            self.context_list = Some(list);
+        }
+        let mut best: Option<(usize, &[Token], &TokenHits)> = None;
+        for (stored, hits) in self.context_list.iter().flatten() {
+            if stored.len().abs_diff(query.len()) > max_distance {
+                continue;
+            }
+            let distance = edit_distance(&query, stored);
+            if distance <= max_distance && best.is_none_or(|(d, _, _)| distance < d) {
+                best = Some((distance, stored, hits));
+            }
+        }
+        Ok(best.map(|(_, stored, hits)| (self.render_tokens(stored, 0), hits.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_context_finds_the_closest_stored_context() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("hello", 3).unwrap();
+        let (found, hits) = stash.nearest_context("hxl", 1).unwrap().unwrap();
+        assert_eq!(found, "hel");
+        assert_eq!(hits.entries[0].value, Token::C('l'));
+        assert_eq!(stash.nearest_context("ell", 1).unwrap().unwrap().0, "ell");
+        assert!(stash.nearest_context("xyz", 1).unwrap().is_none());
+        assert_eq!(
+            edit_distance(&stash.tokenize("kitten"), &stash.tokenize("sitting")),
+            3
+        );
+    }
+
+    #[test]
+    fn new_contexts_are_found_after_the_list_is_cached() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abc", 2).unwrap();
+        assert!(stash.nearest_context("xy", 1).unwrap().is_none());
+        assert!(stash.context_list.is_some());
+        stash.note_string("xyz").unwrap();
+        assert_eq!(stash.nearest_context("xy", 1).unwrap().unwrap().0, "xy");
+        stash.forget_context("xy").unwrap();
+        assert!(stash.nearest_context("xy", 1).unwrap().is_none());
+    }
+}
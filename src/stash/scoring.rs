@@ -0,0 +1,172 @@
+use super::TokenStash;
+use crate::error::Result;
+use crate::token::{total_count, Token, TokenEntry};
+
+/// The share of the observations in `entries` that are `next`.
+pub(super) fn share(entries: &[TokenEntry], next: &Token) -> f64 {
+    let total = total_count(entries);
+    if total == 0 {
+        return 0.0;
+    }
+    entries
+        .iter()
+        .find(|e| &e.value == next)
+        .map_or(0.0, |e| e.count as f64 / total as f64)
+}
+
+impl TokenStash {
+    /// Use add-`k` (Laplace for `k` = 1) smoothing in probability queries; 0 turns it off.
+    ///
+    /// Every token then counts `k` more than observed, out of a vocabulary of
+    /// all tokens in the overall frequencies, so unseen continuations of known
+    /// and unknown contexts get a small share. Negative values are treated as 0.
+    pub fn set_add_k(&mut self, k: f64) {
+        self.add_k = k.max(0.0);
+    }
+
+    /// The number of distinct tokens add-k smoothing spreads its extra counts over.
+    fn vocabulary_size(&self) -> Result<usize> {
+        if self.add_k == 0.0 {
+            return Ok(0);
+        }
+        Ok(self.unigram_counts()?.len().max(1))
+    }
+
+    /// The share of `next` in `entries`, with add-k smoothing over `vocabulary` tokens.
+    fn smoothed_share(&self, entries: &[TokenEntry], next: &Token, vocabulary: usize) -> f64 {
+        if self.add_k == 0.0 {
+            return share(entries, next);
+        }
+        let total = total_count(entries);
+        let count = entries
+            .iter()
+            .find(|e| &e.value == next)
+            .map_or(0, |e| e.count);
+        (count as f64 + self.add_k) / (total as f64 + self.add_k * vocabulary as f64)
+    }
+
+    /// The probability the model assigns to `next` directly following exactly `context`.
+    ///
+    /// This is the count of `next` divided by the total count of all continuations,
+    /// or 0.0 when the context was never seen, unless [add-k
+    /// smoothing](Self::set_add_k) is on.
+    pub fn probability(&self, context: &str, next: char) -> Result<f64> {
+        let tokens = self.tokenize(context);
+        let entries = self.get_next_candidates(&tokens)?;
+        Ok(self.smoothed_share(&entries, &Token::C(next), self.vocabulary_size()?))
+    }
+
+    /// The Shannon entropy, in bits, of what follows exactly `context`.
+    ///
+    /// High values mark contexts where the model is unsure, 0.0 ones where a
+    /// single continuation is known. An unknown context also gives 0.0.
+    pub fn context_entropy(&self, context: &str) -> Result<f64> {
+        let entries = self.get_next_candidates(&self.tokenize(context))?;
+        let total = total_count(&entries);
+        if total == 0 {
+            return Ok(0.0);
+        }
+        Ok(entries
+            .iter()
+            .filter(|e| e.count > 0)
+            .map(|e| {
+                let p = e.count as f64 / total as f64;
+                -p * p.log2()
+            })
+            .sum())
+    }
+
+    /// The perplexity of the model on `text`: the exponentiated average negative
+    /// log-likelihood of each token given up to `context` tokens before it.
+    ///
+    /// Each token is scored against the longest preceding context that was seen
+    /// in training. Tokens that context never led to, and tokens without any known
+    /// context, get the [smoothing floor](Self::set_smoothing_floor) instead of zero,
+    /// or their [add-k](Self::set_add_k) share when that is higher.
+    /// Text with fewer than two tokens has nothing to predict and scores 1.0.
+    pub fn perplexity(&self, text: &str, context: usize) -> Result<f64> {
+        let tokens = self.tokenize(text);
+        if tokens.len() < 2 {
+            return Ok(1.0);
+        }
+        let nll = -self.log_likelihood(&tokens, 1, context)?;
+        Ok((nll / (tokens.len() - 1) as f64).exp())
+    }
+
+    /// The summed log-probability of `tokens[start..]`, each given up to `context`
+    /// tokens before it, scored as in [`perplexity`](Self::perplexity).
+    pub(super) fn log_likelihood(
+        &self,
+        tokens: &[Token],
+        start: usize,
+        context: usize,
+    ) -> Result<f64> {
+        let vocabulary = self.vocabulary_size()?;
+        let mut ll = 0.0;
+        for i in start.max(1)..tokens.len() {
+            let entries = match self.longest_candidates(&tokens[..i], context)? {
+                Some((_, entries)) => entries,
+                None => vec![],
+            };
+            let p = self.smoothed_share(&entries, &tokens[i], vocabulary);
+            ll += p.max(self.smoothing_floor).ln();
+        }
+        Ok(ll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stash::DEFAULT_SMOOTHING_FLOOR;
+
+    #[test]
+    fn probability_is_the_share_of_observations() {
+        let mut stash = TokenStash::in_memory();
+        // "a" -> b (2), c (1)
+        stash.note_text("abacab", 1).unwrap();
+        assert!((stash.probability("a", 'b').unwrap() - 2.0 / 3.0).abs() < 1e-9);
+        assert!((stash.probability("a", 'c').unwrap() - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(stash.probability("a", 'z').unwrap(), 0.0);
+        assert_eq!(stash.probability("z", 'a').unwrap(), 0.0);
+    }
+
+    #[test]
+    fn perplexity_is_low_on_training_text_and_finite_on_unseen_text() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abcabcabc", 2).unwrap();
+        // Every transition is certain after one token of context.
+        assert!((stash.perplexity("abcab", 2).unwrap() - 1.0).abs() < 1e-9);
+        let unseen = stash.perplexity("xyz", 2).unwrap();
+        assert!(unseen.is_finite());
+        assert!((unseen - 1.0 / DEFAULT_SMOOTHING_FLOOR).abs() < 1e-3);
+        stash.set_smoothing_floor(0.5);
+        assert!((stash.perplexity("xyz", 2).unwrap() - 2.0).abs() < 1e-9);
+        assert_eq!(stash.perplexity("a", 2).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn add_k_smoothing_gives_unseen_tokens_a_share() {
+        let mut stash = TokenStash::in_memory();
+        // Vocabulary a, b, c; "a" -> b (2), c (1)
+        stash.note_text("abacab", 1).unwrap();
+        stash.set_add_k(1.0);
+        assert!((stash.probability("a", 'b').unwrap() - 3.0 / 6.0).abs() < 1e-9);
+        assert!((stash.probability("a", 'a').unwrap() - 1.0 / 6.0).abs() < 1e-9);
+        assert!((stash.probability("z", 'a').unwrap() - 1.0 / 3.0).abs() < 1e-9);
+        let smoothed = stash.perplexity("xyz", 1).unwrap();
+        assert!((smoothed - 3.0).abs() < 1e-9);
+        stash.set_add_k(0.0);
+        assert_eq!(stash.probability("a", 'a').unwrap(), 0.0);
+    }
+
+    #[test]
+    fn context_entropy_measures_uncertainty_in_bits() {
+        let mut stash = TokenStash::in_memory();
+        // "a" -> b, c, d, e once each; "b" -> a only.
+        stash.note_text("abacadae", 1).unwrap();
+        assert!((stash.context_entropy("a").unwrap() - 2.0).abs() < 1e-9);
+        assert_eq!(stash.context_entropy("b").unwrap(), 0.0);
+        assert_eq!(stash.context_entropy("z").unwrap(), 0.0);
+    }
+}
@@ -0,0 +1,140 @@
+use super::{top_k, Sampling, TokenStash};
+use crate::error::Result;
+use crate::token::{total_count, Token};
+
+/// A partial continuation explored by [`TokenStash::generate_beam`].
+struct Hypothesis {
+    generated: Vec<Token>,
+    log_prob: f64,
+    done: bool,
+}
+
+impl TokenStash {
+    /// Up to `n` distinct continuations of `input`, most likely first.
+    ///
+    /// Runs `n` independent weighted rollouts of at most `max_len` tokens, drops
+    /// duplicates and ranks the rest by the joint probability of their tokens,
+    /// scored as in [`perplexity`](Self::perplexity). [Seed](Self::set_seed) the
+    /// stash for reproducible results.
+    pub fn generate_n_best(
+        &mut self,
+        input: &str,
+        context: usize,
+        n: usize,
+        max_len: usize,
+    ) -> Result<Vec<String>> {
+        let seed = self.seed_tokens(input);
+        let previous = seed.iter().filter(|t| **t != Token::Bos).count();
+        let mut completions: Vec<(String, f64)> = vec![];
+        for _ in 0..n {
+            let (generated, _) =
+                self.generate_tokens(seed.clone(), context, max_len, &Sampling::Weighted)?;
+            let text = self.render_tokens(&generated, previous);
+            if completions.iter().any(|(t, _)| *t == text) {
+                continue;
+            }
+            let mut tokens = seed.clone();
+            tokens.extend(generated);
+            let score = self.log_likelihood(&tokens, seed.len(), context)?;
+            completions.push((text, score));
+        }
+        completions.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(completions.into_iter().map(|(text, _)| text).collect())
+    }
+
+    /// The continuation of `input` found by beam search with `beam_width` hypotheses.
+    ///
+    /// At every step each hypothesis is extended by the `beam_width` most
+    /// frequent continuations of its longest known context, scored by cumulative
+    /// log-probability, and only the best `beam_width` extensions are kept. A
+    /// hypothesis ends at `Token::Eos`, when nothing follows it, or after
+    /// `max_len` tokens. Unlike sampling this is deterministic.
+    pub fn generate_beam(
+        &self,
+        input: &str,
+        context: usize,
+        beam_width: usize,
+        max_len: usize,
+    ) -> Result<String> {
+        let beam_width = beam_width.max(1);
+        let seed = self.seed_tokens(input);
+        let previous = seed.iter().filter(|t| **t != Token::Bos).count();
+        let mut beam = vec![Hypothesis {
+            generated: vec![],
+            log_prob: 0.0,
+            done: false,
+        }];
+        for _ in 0..max_len {
+            if beam.iter().all(|h| h.done) {
+                break;
+            }
+            let mut next = vec![];
+            for hyp in beam {
+                if hyp.done {
+                    next.push(hyp);
+                    continue;
+                }
+                let mut tokens = seed.clone();
+                tokens.extend(hyp.generated.iter().cloned());
+                let Some((_, entries)) = self.longest_candidates(&tokens, context)? else {
+                    next.push(Hypothesis { done: true, ..hyp });
+                    continue;
+                };
+                let total = total_count(&entries);
+                for e in top_k(entries, beam_width) {
+                    let log_prob = hyp.log_prob + (e.count as f64 / total as f64).ln();
+                    let mut generated = hyp.generated.clone();
+                    let done = e.value == Token::Eos;
+                    if !done {
+                        generated.push(e.value);
+                    }
+                    next.push(Hypothesis {
+                        generated,
+                        log_prob,
+                        done,
+                    });
+                }
+            }
+            next.sort_by(|a, b| b.log_prob.total_cmp(&a.log_prob));
+            next.truncate(beam_width);
+            beam = next;
+        }
+        if beam[0].generated.is_empty() {
+            self.warn_if_untrained()?;
+        }
+        Ok(self.render_tokens(&beam[0].generated, previous))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::EosMode;
+
+    #[test]
+    fn n_best_returns_distinct_completions_most_likely_first() {
+        let mut stash = TokenStash::in_memory();
+        stash.set_seed(3);
+        // After "a": "b" three times, "c" once.
+        stash.note_text("ab.ab.ab.ac.", 1).unwrap();
+        let best = stash.generate_n_best("a", 1, 20, 1).unwrap();
+        assert_eq!(best, vec!["b".to_string(), "c".to_string()]);
+        assert!(stash.generate_n_best("a", 1, 0, 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn beam_search_finds_the_likelier_path() {
+        let mut stash = TokenStash::in_memory();
+        // "a" -> b (3), c (2); "b" -> p, q, r equally; "c" -> x always.
+        stash.note_text("abp.abq.abr.acx.acx.", 1).unwrap();
+        // Greedy takes "b" and then has to guess; the beam sees "cx" is likelier.
+        assert_eq!(stash.generate_beam("a", 1, 1, 2).unwrap(), "bp");
+        assert_eq!(stash.generate_beam("a", 1, 2, 2).unwrap(), "cx");
+
+        let mut stash = TokenStash::in_memory();
+        stash.set_eos_mode(EosMode::Document);
+        stash.note_text("ab", 1).unwrap();
+        assert_eq!(stash.generate_beam("a", 1, 3, 10).unwrap(), "b");
+        assert_eq!(stash.generate_beam("z", 1, 3, 10).unwrap(), "");
+    }
+}
@@ -0,0 +1,179 @@
+use bincode::error::DecodeError;
+use bincode::{Decode, Encode};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::{merge_hits, ContextHits, StoredHits, TokenStash};
+use crate::error::Result;
+use crate::token::{EosMode, Normalization, TokenMode};
+
+const MAGIC: &[u8; 6] = b"HMSNAP";
+const VERSION: u8 = 1;
+
+/// The settings a model was trained with, stored ahead of its contexts.
+///
+/// The order is not recorded separately: it is the length of the longest
+/// context in the snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+struct SnapshotHeader {
+    mode: u8,
+    normalization: u8,
+    eos_mode: u8,
+    bos: bool,
+}
+
+fn invalid(what: &str) -> DecodeError {
+    DecodeError::OtherString(format!("invalid snapshot: {}", what))
+}
+
+impl SnapshotHeader {
+    fn of(stash: &TokenStash) -> Self {
+        SnapshotHeader {
+            mode: match stash.mode {
+                TokenMode::Char => 0,
+                TokenMode::Word => 1,
+                TokenMode::Byte => 2,
+            },
+            normalization: match stash.normalization {
+                Normalization::None => 0,
+                Normalization::Lowercase => 1,
+                Normalization::LowercaseStripAccents => 2,
+            },
+            eos_mode: match stash.eos_mode {
+                EosMode::None => 0,
+                EosMode::Line => 1,
+                EosMode::Document => 2,
+            },
+            bos: stash.bos,
+        }
+    }
+
+    fn mode(&self) -> std::result::Result<TokenMode, DecodeError> {
+        match self.mode {
+            0 => Ok(TokenMode::Char),
+            1 => Ok(TokenMode::Word),
+            2 => Ok(TokenMode::Byte),
+            _ => Err(invalid("token mode")),
+        }
+    }
+
+    fn apply(&self, stash: &mut TokenStash) -> std::result::Result<(), DecodeError> {
+        stash.set_normalization(match self.normalization {
+            0 => Normalization::None,
+            1 => Normalization::Lowercase,
+            2 => Normalization::LowercaseStripAccents,
+            _ => return Err(invalid("normalization")),
+        });
+        stash.set_eos_mode(match self.eos_mode {
+            0 => EosMode::None,
+            1 => EosMode::Line,
+            2 => EosMode::Document,
+            _ => return Err(invalid("eos mode")),
+        });
+        stash.set_bos(self.bos);
+        Ok(())
+    }
+}
+
+impl TokenStash {
+    /// Write the whole model, with its tokenizing settings, to a single binary file at `path`.
+    ///
+    /// Contexts are streamed one at a time, so the model never has to fit in
+    /// memory. Keys are not saved; [`load_snapshot`](Self::load_snapshot)
+    /// recomputes them.
+    pub fn save_snapshot(&mut self, path: &Path) -> Result<()> {
+        let config = bincode::config::standard();
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        bincode::encode_into_std_write(SnapshotHeader::of(self), &mut writer, config)?;
+        self.for_each_context(|_, context, hits| {
+            let record = Some(ContextHits::new(context.to_vec(), hits));
+            bincode::encode_into_std_write(record, &mut writer, config)?;
+            Ok(())
+        })?;
+        bincode::encode_into_std_write(None::<ContextHits>, &mut writer, config)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Open (or create) the model in `{prefix}/db` and fill it from the snapshot at `path`.
+    ///
+    /// The returned stash uses the token mode, normalization and sequence
+    /// markers the snapshot was saved with. The database must not hold any
+    /// data yet; loading into a trained model is an error.
+    pub fn load_snapshot(prefix: &str, path: &Path) -> Result<Self> {
+        let config = bincode::config::standard();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 7];
+        reader.read_exact(&mut magic)?;
+        if &magic[..6] != MAGIC {
+            return Err(invalid("not a snapshot file").into());
+        }
+        if magic[6] != VERSION {
+            return Err(invalid("unknown version").into());
+        }
+        let header: SnapshotHeader = bincode::decode_from_std_read(&mut reader, config)?;
+        let mut stash = TokenStash::with_mode(prefix, header.mode()?)?;
+        header.apply(&mut stash)?;
+        if stash.is_trained()? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a model", prefix),
+            )
+            .into());
+        }
+
+        let mut batch: HashMap<String, StoredHits> = HashMap::new();
+        while let Some(record) =
+            bincode::decode_from_std_read::<Option<ContextHits>, _, _>(&mut reader, config)?
+        {
+            let stored = match batch.entry(stash.hash_tokens(&record.context)) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(StoredHits::default()),
+            };
+            merge_hits(stored.get_or_insert(&record.context), &record.hits);
+            if batch.len() >= stash.cache_capacity {
+                stash.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        stash.write_batch(batch)?;
+        Ok(stash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_restores_the_model_and_its_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let mut stash = TokenStash::with_mode(source.to_str().unwrap(), TokenMode::Word).unwrap();
+        stash.set_normalization(Normalization::Lowercase);
+        stash.note_text("The cat sat. the cat ran.", 2).unwrap();
+        let snapshot = dir.path().join("model.snap");
+        stash.save_snapshot(&snapshot).unwrap();
+
+        let target = dir.path().join("target");
+        let target = target.to_str().unwrap();
+        let mut restored = TokenStash::load_snapshot(target, &snapshot).unwrap();
+        assert_eq!(restored.mode(), TokenMode::Word);
+        assert_eq!(restored.stats().unwrap(), stash.stats().unwrap());
+        assert_eq!(
+            restored.candidates("THE").unwrap(),
+            stash.candidates("the").unwrap()
+        );
+        drop(restored);
+
+        // The target is no longer empty.
+        assert!(TokenStash::load_snapshot(target, &snapshot).is_err());
+        std::fs::write(&snapshot, b"not a snapshot").unwrap();
+        let fresh = dir.path().join("fresh");
+        assert!(TokenStash::load_snapshot(fresh.to_str().unwrap(), &snapshot).is_err());
+    }
+}
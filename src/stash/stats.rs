@@ -0,0 +1,172 @@
+use std::fmt;
+
+use super::{TokenStash, RESERVED_PREFIX};
+use crate::error::Result;
+use crate::token::{total_count, Token};
+
+/// Summary of how much a model has been trained.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelStats {
+    /// Number of distinct stored contexts.
+    pub contexts: usize,
+    /// Number of `TokenEntry` rows across all contexts.
+    pub entries: usize,
+    /// Sum of all entry counts.
+    pub observations: u64,
+    /// Average number of distinct continuations per context.
+    pub avg_branching: f64,
+    /// Largest number of distinct continuations of any single context.
+    pub max_branching: usize,
+}
+
+impl fmt::Display for ModelStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "contexts:      {}", self.contexts)?;
+        writeln!(f, "entries:       {}", self.entries)?;
+        writeln!(f, "observations:  {}", self.observations)?;
+        writeln!(f, "avg branching: {:.2}", self.avg_branching)?;
+        write!(f, "max branching: {}", self.max_branching)
+    }
+}
+
+/// How much of the database the contexts of one order take, from [`TokenStash::size_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OrderSize {
+    /// Number of tokens in these contexts.
+    pub order: usize,
+    /// Number of stored contexts of this order.
+    pub contexts: usize,
+    /// Encoded size of their keys and values, in bytes.
+    pub bytes: usize,
+}
+
+impl TokenStash {
+    /// Walk the whole database and summarize its contents.
+    pub fn stats(&mut self) -> Result<ModelStats> {
+        let mut stats = ModelStats::default();
+        self.for_each_context(|_, context, hits| {
+            // The empty context holds the overall token frequencies, not transitions.
+            if context.is_empty() {
+                return Ok(());
+            }
+            stats.contexts += 1;
+            stats.entries += hits.entries.len();
+            let observations = u64::try_from(total_count(&hits.entries)).unwrap_or(u64::MAX);
+            stats.observations = stats.observations.saturating_add(observations);
+            stats.max_branching = stats.max_branching.max(hits.entries.len());
+            Ok(())
+        })?;
+        if stats.contexts > 0 {
+            stats.avg_branching = stats.entries as f64 / stats.contexts as f64;
+        }
+        Ok(stats)
+    }
+
+    /// Where the size of the database goes, one entry per context order, shortest first.
+    ///
+    /// Sizes are those of the encoded keys and values, before any overhead of
+    /// the database itself. Order 0 holds the overall token frequencies.
+    pub fn size_report(&mut self) -> Result<Vec<OrderSize>> {
+        self.flush()?;
+        let mut sizes: Vec<OrderSize> = vec![];
+        self.for_each_own_key(&mut |key, value| {
+            if key.starts_with(RESERVED_PREFIX) {
+                return Ok(());
+            }
+            let bytes = key.len() + value.len();
+            let buckets = Self::decode_hits(value)?.buckets;
+            // Contexts sharing a key always have its length, so they share an order too.
+            let Some(first) = buckets.first() else {
+                return Ok(());
+            };
+            let order = first.context.len();
+            if sizes.len() <= order {
+                sizes.resize_with(order + 1, OrderSize::default);
+            }
+            let size = &mut sizes[order];
+            size.contexts += buckets.len();
+            size.bytes += bytes;
+            Ok(())
+        })?;
+        for (order, size) in sizes.iter_mut().enumerate() {
+            size.order = order;
+        }
+        sizes.retain(|s| s.contexts > 0);
+        Ok(sizes)
+    }
+
+    /// The `top` most frequent n-grams of `order` tokens, with their counts, most frequent first.
+    ///
+    /// Each n-gram is a stored context of `order - 1` tokens followed by one of
+    /// its continuations, so an `order` of 2 lists bigrams and an `order` of 1
+    /// lists single tokens by overall frequency. Orders above the one the model
+    /// was trained with find nothing. Only contexts of `order - 1` tokens are
+    /// read, not the whole database.
+    pub fn ngram_report(&mut self, order: usize, top: usize) -> Result<Vec<(Vec<Token>, u64)>> {
+        let mut ngrams = vec![];
+        if order == 0 {
+            return Ok(ngrams);
+        }
+        self.for_each_context_of_order(order - 1, |_, context, hits| {
+            for e in hits.entries {
+                let mut ngram = context.to_vec();
+                ngram.push(e.value);
+                ngrams.push((ngram, e.count));
+            }
+            Ok(())
+        })?;
+        ngrams.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        ngrams.truncate(top);
+        Ok(ngrams)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_count_contexts_entries_and_observations() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stash = TokenStash::new(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(stash.stats().unwrap(), ModelStats::default());
+        stash.note_text("abac", 1).unwrap();
+        let stats = stash.stats().unwrap();
+        // "a" -> b, c; "b" -> a
+        assert_eq!(stats.contexts, 2);
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.observations, 3);
+        assert_eq!(stats.max_branching, 2);
+        assert_eq!(stats.avg_branching, 1.5);
+    }
+
+    #[test]
+    fn ngram_report_lists_the_most_frequent_ngrams() {
+        let mut stash = TokenStash::in_memory();
+        stash.note_text("abcabcabd", 2).unwrap();
+        // ab (3), then bc and ca (2 each), then bd (1)
+        let bigrams = stash.ngram_report(2, 3).unwrap();
+        assert_eq!(bigrams.len(), 3);
+        assert_eq!(bigrams[0], (vec![Token::C('a'), Token::C('b')], 3));
+        assert_eq!(bigrams[1].1, 2);
+        assert_eq!(bigrams[2].1, 2);
+        let trigrams = stash.ngram_report(3, 10).unwrap();
+        assert_eq!(trigrams.len(), 4);
+        assert!(trigrams.iter().all(|(ngram, _)| ngram.len() == 3));
+        assert_eq!(stash.ngram_report(1, 1).unwrap()[0].1, 3);
+        assert!(stash.ngram_report(4, 10).unwrap().is_empty());
+        assert!(stash.ngram_report(0, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn size_report_breaks_the_database_down_by_order() {
+        let mut stash = TokenStash::in_memory();
+        assert!(stash.size_report().unwrap().is_empty());
+        stash.note_text("abcab", 2).unwrap();
+        let sizes = stash.size_report().unwrap();
+        let orders: Vec<_> = sizes.iter().map(|s| (s.order, s.contexts)).collect();
+        // "", then a, b, c, then ab, bc, ca.
+        assert_eq!(orders, vec![(0, 1), (1, 3), (2, 3)]);
+        assert!(sizes[2].bytes > sizes[1].bytes);
+    }
+}
@@ -0,0 +1,211 @@
+use bincode::error::DecodeError;
+use std::collections::HashMap;
+
+use super::{merge_hits, StoredHits, TokenStash, RESERVED_PREFIX};
+use crate::error::Result;
+use crate::key::short_key;
+
+/// The reserved key holding the storage format version.
+pub(super) const FORMAT_KEY: &str = "!format";
+
+/// Version 3 keys short contexts by their encoding instead of a digest; version 2
+/// put the context length in front of every key; version 1 had no marker.
+const FORMAT_VERSION: u8 = 3;
+
+impl TokenStash {
+    /// Bring a database written by an older version of this crate up to the current key layout.
+    ///
+    /// Version 1 keys did not start with the context length, and before version 3
+    /// short contexts were hashed too. Outdated keys are rewritten in a single
+    /// transaction, which keeps them in memory while it runs. Returns whether
+    /// anything was migrated. Keys of [namespaced](crate::SharedStore::namespace)
+    /// models sharing the store are left alone. Stashes opened from a
+    /// directory do this on their own; call it before anything else on a
+    /// stash built [`with_store`](Self::with_store) around an existing store.
+    pub fn upgrade_format(&mut self) -> Result<bool> {
+        let from = match self.store.get(FORMAT_KEY)?.as_deref() {
+            Some([FORMAT_VERSION]) => {
+                self.format_marked = true;
+                return Ok(false);
+            }
+            Some([2]) => 2,
+            None => 1,
+            Some(other) => {
+                return Err(DecodeError::OtherString(format!(
+                    "unsupported database format {:?}",
+                    other
+                ))
+                .into())
+            }
+        };
+
+        // Namespaced models sharing the store have keys that look like ours,
+        // but are not ours to migrate.
+        let mut migrated: HashMap<String, StoredHits> = HashMap::new();
+        let mut changes = vec![];
+        self.for_each_own_key(&mut |key, value| {
+            if key.starts_with(RESERVED_PREFIX) {
+                return Ok(());
+            }
+            let buckets = Self::decode_hits(value)?.buckets;
+            // Version 1 keys were the bare digest, so prefixing the length gives the
+            // version 2 key under whichever key strategy the model was trained with,
+            // and short contexts have the same key under every strategy.
+            let new_keys: Vec<String> = buckets
+                .iter()
+                .map(|b| {
+                    let len = b.context.len();
+                    match short_key(&b.context) {
+                        Some(short) => format!("{}:{}", len, short),
+                        None if from == 1 => format!("{}:{}", len, key),
+                        None => key.to_string(),
+                    }
+                })
+                .collect();
+            if new_keys.iter().all(|k| k == key) {
+                return Ok(());
+            }
+            changes.push((key.to_string(), None));
+            for (bucket, new_key) in buckets.into_iter().zip(new_keys) {
+                let stored = migrated.entry(new_key).or_default();
+                merge_hits(stored.get_or_insert(&bucket.context), &bucket.hits);
+            }
+            Ok(())
+        })?;
+        if changes.is_empty() && from == 1 {
+            return Ok(false);
+        }
+        info!(
+            "migrating {} keys to format {}",
+            changes.len(),
+            FORMAT_VERSION
+        );
+        let migrated_any = !changes.is_empty();
+        for (key, hits) in &migrated {
+            changes.push((key.clone(), Some(Self::encode_hits(hits)?)));
+        }
+        changes.push((FORMAT_KEY.to_string(), Some(vec![FORMAT_VERSION])));
+        self.format_marked = true;
+        self.apply_changes(changes)?;
+        Ok(migrated_any)
+    }
+
+    /// Add the format marker to `changes` if the database does not carry it yet.
+    pub(super) fn mark_format(
+        &mut self,
+        changes: &mut Vec<(String, Option<Vec<u8>>)>,
+    ) -> Result<()> {
+        if !self.format_marked {
+            if self.store.get(FORMAT_KEY)?.is_none() {
+                changes.push((FORMAT_KEY.to_string(), Some(vec![FORMAT_VERSION])));
+            }
+            self.format_marked = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stash::add_count;
+    use crate::store::{MemoryStore, RedbStore, Store};
+    use crate::token::{Token, TokenMode};
+    use crate::HashmemError;
+
+    /// A store holding `b` after `context`, twice, under `key`.
+    fn store_with(key: &str, context: &[Token]) -> MemoryStore {
+        let mut hits = StoredHits::default();
+        add_count(hits.get_or_insert(context), &Token::C('b'), 2);
+        let mut store = MemoryStore::default();
+        store
+            .put(key, TokenStash::encode_hits(&hits).unwrap())
+            .unwrap();
+        store
+    }
+
+    /// The key every context had before version 3, without the length.
+    fn digest_of(context: &[Token]) -> String {
+        let encoded = bincode::encode_to_vec(context, bincode::config::standard()).unwrap();
+        sha256::digest(&encoded[..])
+    }
+
+    #[test]
+    fn version_one_databases_are_migrated() {
+        for input in ["a", "abcdefghij"] {
+            let context: Vec<Token> = input.chars().map(Token::C).collect();
+            let legacy = digest_of(&context);
+            let store = store_with(&legacy, &context);
+            let mut stash = TokenStash::with_store(Box::new(store), TokenMode::Char);
+            assert!(stash.predict_token(input).unwrap().is_empty());
+            assert!(stash.upgrade_format().unwrap());
+            assert_eq!(stash.predict_token(input).unwrap()[0].count, 2);
+            assert!(!stash.upgrade_format().unwrap());
+            assert!(stash.store.get(&legacy).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn version_two_short_contexts_are_rekeyed() {
+        let context = [Token::C('a')];
+        let old = format!("1:{}", digest_of(&context));
+        let mut store = store_with(&old, &context);
+        store.put(FORMAT_KEY, vec![2]).unwrap();
+        let mut stash = TokenStash::with_store(Box::new(store), TokenMode::Char);
+        assert!(stash.upgrade_format().unwrap());
+        assert_eq!(stash.predict_token("a").unwrap()[0].count, 2);
+        assert!(stash.store.get(&old).unwrap().is_none());
+        assert_eq!(
+            stash.store.get(FORMAT_KEY).unwrap(),
+            Some(vec![FORMAT_VERSION])
+        );
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        let mut store = MemoryStore::default();
+        store.put(FORMAT_KEY, vec![99]).unwrap();
+        let mut stash = TokenStash::with_store(Box::new(store), TokenMode::Char);
+        assert!(stash.upgrade_format().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        TokenStash::new(prefix).unwrap().note_text("ab", 1).unwrap();
+        let mut stash = TokenStash::new(prefix).unwrap();
+        assert!(stash.format_marked);
+        assert!(!stash.upgrade_format().unwrap());
+    }
+
+    #[test]
+    fn namespaced_models_are_not_migrated_into_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        TokenStash::new_namespaced(prefix, "en")
+            .unwrap()
+            .note_text("abac", 1)
+            .unwrap();
+        let stash = TokenStash::new(prefix).unwrap();
+        assert!(stash.predict_token("a").unwrap().is_empty());
+        drop(stash);
+        let en = TokenStash::new_namespaced(prefix, "en").unwrap();
+        assert_eq!(en.candidates("a").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn databases_without_stored_contexts_ask_for_a_retrain() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().to_str().unwrap();
+        {
+            // The first releases keyed a bare `TokenHits` by the digest alone.
+            let mut store = RedbStore::open(&format!("{}/db", prefix)).unwrap();
+            let context = [Token::C('a')];
+            store
+                .put(&digest_of(&context), vec![2, 0, b'b', 2, 1, 7, 1])
+                .unwrap();
+        }
+        assert!(matches!(
+            TokenStash::new(prefix),
+            Err(HashmemError::RetrainRequired(_))
+        ));
+    }
+}
@@ -0,0 +1,616 @@
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, TableError};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::error::Result;
+
+const HITS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("token_hits");
+
+/// The keys and values of a [`Store`], from [`Store::iter`].
+pub type StoreIter<'a> = Box<dyn Iterator<Item = Result<(String, Vec<u8>)>> + 'a>;
+
+/// A key-value backend holding the encoded hits of every context.
+pub trait Store: Send + Sync {
+    /// The value stored under `key`, if any.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// The values stored under each of `keys`, in the same order.
+    ///
+    /// The default implementation calls [`get`](Self::get) once per key;
+    /// backends override it to answer the whole batch from a single read.
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Apply all `changes` at once: `Some` stores a value, `None` removes the key.
+    fn apply(&mut self, changes: Vec<(String, Option<Vec<u8>>)>) -> Result<()>;
+
+    /// Call `f` with every key and value, in key order.
+    fn for_each(&self, f: &mut dyn FnMut(&str, &[u8]) -> Result<()>) -> Result<()>;
+
+    /// Every key and value, in key order, read lazily where the backend allows it.
+    ///
+    /// The default implementation collects everything through
+    /// [`for_each`](Self::for_each) first.
+    fn iter(&self) -> Result<StoreIter<'_>> {
+        let mut all = vec![];
+        self.for_each(&mut |key, value| {
+            all.push(Ok((key.to_string(), value.to_vec())));
+            Ok(())
+        })?;
+        Ok(Box::new(all.into_iter()))
+    }
+
+    /// Call `f` with every key starting with `prefix` and its value, in key order.
+    fn for_each_prefix(
+        &self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.for_each(&mut |key, value| {
+            if key.starts_with(prefix) {
+                f(key, value)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Store `value` under `key`.
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.apply(vec![(key.to_string(), Some(value))])
+    }
+
+    /// Whether no key is stored at all.
+    fn is_empty(&self) -> Result<bool> {
+        let mut empty = true;
+        self.for_each(&mut |_, _| {
+            empty = false;
+            Ok(())
+        })?;
+        Ok(empty)
+    }
+}
+
+/// Settings applied when a [`RedbStore`] is opened.
+///
+/// redb compresses nothing and has no separate write buffer, so unlike LevelDB
+/// the page cache is its only tunable. It has no Bloom filters either; this
+/// crate can keep one in memory instead, see [`BloomStore`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreOptions {
+    /// Bytes of page cache, or `None` for redb's default (1 GiB).
+    pub cache_size: Option<usize>,
+    /// Wrap the database in a [`BloomStore`] with this many filter bits per
+    /// key, or `None` for no filter. 10 bits give about 1% false positives.
+    pub bloom_bits_per_key: Option<usize>,
+    /// Check the whole file on open and repair it if it is damaged. This is
+    /// slow, and unnecessary after a crash: redb recovers from those anyway.
+    pub repair: bool,
+}
+
+/// A [`Store`] in a redb database file; each [`apply`](Store::apply) is one transaction.
+pub struct RedbStore {
+    database: Database,
+}
+
+impl RedbStore {
+    /// Open (or create) the database at `path`, creating its parent directory if needed.
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_with(path, &StoreOptions::default())
+    }
+
+    /// Like [`open`](Self::open), but with the given settings.
+    pub fn open_with(path: &str, options: &StoreOptions) -> Result<Self> {
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut builder = Database::builder();
+        if let Some(cache_size) = options.cache_size {
+            builder.set_cache_size(cache_size);
+        }
+        let mut database = builder.create(path)?;
+        if options.repair && !database.check_integrity()? {
+            warn!("repaired the damaged database at {}", path);
+        }
+        Ok(RedbStore { database })
+    }
+}
+
+impl Store for RedbStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let read_txn = self.database.begin_read()?;
+        let table = match read_txn.open_table(HITS_TABLE) {
+            Ok(table) => table,
+            // Table doesn't exist yet
+            Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(table.get(key)?.map(|data| data.value().to_vec()))
+    }
+
+    /// Reads every key in one transaction, visiting them in key order for locality.
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        let read_txn = self.database.begin_read()?;
+        let table = match read_txn.open_table(HITS_TABLE) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(vec![None; keys.len()]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by_key(|&i| keys[i]);
+        let mut values = vec![None; keys.len()];
+        for i in order {
+            values[i] = table.get(keys[i])?.map(|data| data.value().to_vec());
+        }
+        Ok(values)
+    }
+
+    fn apply(&mut self, changes: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let write_txn = self.database.begin_write()?;
+        {
+            let mut table = write_txn.open_table(HITS_TABLE)?;
+            for (key, value) in &changes {
+                match value {
+                    Some(value) => table.insert(key.as_str(), value.as_slice())?,
+                    None => table.remove(key.as_str())?,
+                };
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &[u8]) -> Result<()>) -> Result<()> {
+        let read_txn = self.database.begin_read()?;
+        let table = match read_txn.open_table(HITS_TABLE) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for item in table.iter()? {
+            let (key, value) = item?;
+            f(key.value(), value.value())?;
+        }
+        Ok(())
+    }
+
+    fn iter(&self) -> Result<StoreIter<'_>> {
+        let read_txn = self.database.begin_read()?;
+        let table = match read_txn.open_table(HITS_TABLE) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(Box::new(std::iter::empty())),
+            Err(e) => return Err(e.into()),
+        };
+        // The range keeps its own reference to the snapshot it reads.
+        Ok(Box::new(table.range::<&str>(..)?.map(|item| {
+            let (key, value) = item?;
+            Ok((key.value().to_string(), value.value().to_vec()))
+        })))
+    }
+
+    fn for_each_prefix(
+        &self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let read_txn = self.database.begin_read()?;
+        let table = match read_txn.open_table(HITS_TABLE) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for item in table.range(prefix..)? {
+            let (key, value) = item?;
+            if !key.value().starts_with(prefix) {
+                break;
+            }
+            f(key.value(), value.value())?;
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        let read_txn = self.database.begin_read()?;
+        match read_txn.open_table(HITS_TABLE) {
+            Ok(table) => Ok(table.is_empty()?),
+            Err(TableError::TableDoesNotExist(_)) => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// An in-memory Bloom filter over string keys.
+#[derive(Debug, Clone)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    fn new(capacity: usize, bits_per_key: usize) -> Self {
+        let capacity = capacity.max(1024);
+        let bits_per_key = bits_per_key.max(1);
+        BloomFilter {
+            bits: vec![0; (capacity * bits_per_key).div_ceil(64)],
+            // ln 2 * bits per key hash functions minimize false positives.
+            hashes: ((bits_per_key as f64 * 0.69).round() as u32).clamp(1, 16),
+            capacity,
+            len: 0,
+        }
+    }
+
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let h1 = hasher.finish();
+        7u8.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+        let m = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+
+    fn insert(&mut self, key: &str) {
+        for bit in self.positions(key).collect::<Vec<_>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    fn may_contain(&self, key: &str) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// A [`Store`] that answers lookups of absent keys from an in-memory Bloom filter.
+///
+/// Prediction tries the longest contexts first, and most of those were never
+/// seen, so the filter saves a database read for nearly every miss. Building
+/// it reads every key once; it grows by rebuilding when it fills up. Removed
+/// keys stay in the filter until then, which only costs a lookup.
+pub struct BloomStore<S> {
+    inner: S,
+    bits_per_key: usize,
+    filter: BloomFilter,
+}
+
+impl<S: Store> BloomStore<S> {
+    /// Wrap `inner`, with `bits_per_key` filter bits per stored key.
+    pub fn new(inner: S, bits_per_key: usize) -> Result<Self> {
+        let mut store = BloomStore {
+            inner,
+            bits_per_key,
+            filter: BloomFilter::new(0, bits_per_key),
+        };
+        store.rebuild(0)?;
+        Ok(store)
+    }
+
+    /// Refill the filter from the stored keys, with room for at least `min_capacity`.
+    fn rebuild(&mut self, min_capacity: usize) -> Result<()> {
+        let mut keys = vec![];
+        self.inner.for_each(&mut |key, _| {
+            keys.push(key.to_string());
+            Ok(())
+        })?;
+        let capacity = (keys.len() * 2).max(min_capacity);
+        self.filter = BloomFilter::new(capacity, self.bits_per_key);
+        for key in &keys {
+            self.filter.insert(key);
+        }
+        Ok(())
+    }
+}
+
+impl<S: Store> Store for BloomStore<S> {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        if !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+        self.inner.get(key)
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        let (present, lookups): (Vec<usize>, Vec<&str>) = keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| self.filter.may_contain(key))
+            .map(|(i, key)| (i, *key))
+            .unzip();
+        let mut values = vec![None; keys.len()];
+        for (i, value) in present.into_iter().zip(self.inner.get_many(&lookups)?) {
+            values[i] = value;
+        }
+        Ok(values)
+    }
+
+    fn apply(&mut self, changes: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+        let added: Vec<String> = changes
+            .iter()
+            .filter(|(_, value)| value.is_some())
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.inner.apply(changes)?;
+        if self.filter.len + added.len() > self.filter.capacity {
+            return self.rebuild((self.filter.len + added.len()) * 2);
+        }
+        for key in &added {
+            self.filter.insert(key);
+        }
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &[u8]) -> Result<()>) -> Result<()> {
+        self.inner.for_each(f)
+    }
+
+    fn iter(&self) -> Result<StoreIter<'_>> {
+        self.inner.iter()
+    }
+
+    fn for_each_prefix(
+        &self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.inner.for_each_prefix(prefix, f)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+}
+
+/// A [`Store`] kept entirely in memory and lost when dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    values: BTreeMap<String, Vec<u8>>,
+}
+
+impl Store for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    fn apply(&mut self, changes: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+        for (key, value) in changes {
+            match value {
+                Some(value) => self.values.insert(key, value),
+                None => self.values.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &[u8]) -> Result<()>) -> Result<()> {
+        for (key, value) in &self.values {
+            f(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn iter(&self) -> Result<StoreIter<'_>> {
+        Ok(Box::new(
+            self.values
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        ))
+    }
+
+    fn for_each_prefix(
+        &self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        for (key, value) in self.values.range(prefix.to_string()..) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            f(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.values.is_empty())
+    }
+}
+
+/// A [`Store`] several models can use at once, each under its own [namespace](Self::namespace).
+///
+/// Cloning it is cheap and shares the underlying store.
+#[derive(Clone)]
+pub struct SharedStore {
+    inner: Arc<Mutex<Box<dyn Store>>>,
+}
+
+impl SharedStore {
+    /// Share `store`.
+    pub fn new(store: Box<dyn Store>) -> Self {
+        SharedStore {
+            inner: Arc::new(Mutex::new(store)),
+        }
+    }
+
+    /// A view of the shared store that only sees the keys of `namespace`.
+    pub fn namespace(&self, namespace: &str) -> NamespacedStore {
+        NamespacedStore {
+            inner: self.inner.clone(),
+            // The length keeps namespaces that are prefixes of each other apart.
+            key_prefix: format!("{}:{}:", namespace.len(), namespace),
+        }
+    }
+}
+
+/// The `{len}:{namespace}:` prefix `key` would have in a [`SharedStore`]
+/// namespace, if it has that shape.
+pub(crate) fn namespace_prefix(key: &str) -> Option<&str> {
+    let (len, rest) = key.split_once(':')?;
+    let namespace = rest.get(..len.parse().ok()?)?;
+    let end = key.len() - rest.len() + namespace.len();
+    key[end..].starts_with(':').then(|| &key[..end + 1])
+}
+
+/// One namespace of a [`SharedStore`]: every key is stored behind the namespace,
+/// so models in different namespaces never see each other's statistics.
+pub struct NamespacedStore {
+    inner: Arc<Mutex<Box<dyn Store>>>,
+    key_prefix: String,
+}
+
+impl NamespacedStore {
+    fn lock(&self) -> MutexGuard<'_, Box<dyn Store>> {
+        // A panic elsewhere cannot leave a store half written: apply is atomic.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Store for NamespacedStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.lock().get(&format!("{}{}", self.key_prefix, key))
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        let keys: Vec<String> = keys
+            .iter()
+            .map(|key| format!("{}{}", self.key_prefix, key))
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        self.lock().get_many(&keys)
+    }
+
+    fn apply(&mut self, changes: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+        let changes = changes
+            .into_iter()
+            .map(|(key, value)| (format!("{}{}", self.key_prefix, key), value))
+            .collect();
+        self.lock().apply(changes)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &[u8]) -> Result<()>) -> Result<()> {
+        self.lock()
+            .for_each(&mut |key, value| match key.strip_prefix(&self.key_prefix) {
+                Some(key) => f(key, value),
+                None => Ok(()),
+            })
+    }
+
+    fn for_each_prefix(
+        &self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.lock().for_each_prefix(
+            &format!("{}{}", self.key_prefix, prefix),
+            &mut |key, value| f(&key[self.key_prefix.len()..], value),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(store: &mut dyn Store) {
+        assert_eq!(store.get("a").unwrap(), None);
+        assert!(store.is_empty().unwrap());
+        store.put("b", vec![2]).unwrap();
+        store
+            .apply(vec![
+                ("a".to_string(), Some(vec![1])),
+                ("c".to_string(), Some(vec![3])),
+                ("b".to_string(), None),
+            ])
+            .unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(vec![1]));
+        assert_eq!(store.get("b").unwrap(), None);
+        assert!(!store.is_empty().unwrap());
+        let mut seen = vec![];
+        store
+            .for_each(&mut |k, v| {
+                seen.push((k.to_string(), v.to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            seen,
+            vec![("a".to_string(), vec![1]), ("c".to_string(), vec![3])]
+        );
+
+        store.put("ab", vec![4]).unwrap();
+        let mut seen = vec![];
+        store
+            .for_each_prefix("a", &mut |k, _| {
+                seen.push(k.to_string());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(seen, vec!["a".to_string(), "ab".to_string()]);
+
+        let all: Vec<_> = store.iter().unwrap().map(|item| item.unwrap().0).collect();
+        assert_eq!(all, vec!["a", "ab", "c"]);
+
+        assert_eq!(
+            store.get_many(&["c", "missing", "a", "c"]).unwrap(),
+            vec![Some(vec![3]), None, Some(vec![1]), Some(vec![3])]
+        );
+        assert!(store.get_many(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_and_redb_stores_behave_alike() {
+        exercise(&mut MemoryStore::default());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        exercise(&mut RedbStore::open(path.to_str().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn bloom_store_behaves_like_its_inner_store_and_skips_misses() {
+        exercise(&mut BloomStore::new(MemoryStore::default(), 10).unwrap());
+
+        let mut inner = MemoryStore::default();
+        inner.put("old", vec![1]).unwrap();
+        let mut store = BloomStore::new(inner, 10).unwrap();
+        assert_eq!(store.get("old").unwrap(), Some(vec![1]));
+        // Outgrow the initial filter to force a rebuild.
+        let changes = (0..3000)
+            .map(|i| (format!("k{}", i), Some(vec![2])))
+            .collect();
+        store.apply(changes).unwrap();
+        assert!((0..3000).all(|i| store.get(&format!("k{}", i)).unwrap().is_some()));
+        let false_positives = (0..3000)
+            .filter(|i| store.filter.may_contain(&format!("missing{}", i)))
+            .count();
+        assert!(false_positives < 150, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn namespaces_of_a_shared_store_are_isolated() {
+        let shared = SharedStore::new(Box::new(MemoryStore::default()));
+        exercise(&mut shared.namespace("a"));
+        exercise(&mut shared.namespace("a:"));
+        let other = shared.namespace("b");
+        assert!(other.is_empty().unwrap());
+        assert_eq!(other.get("a").unwrap(), None);
+    }
+
+    #[test]
+    fn damaged_files_are_reported_as_corrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        std::fs::write(&path, vec![0x5a; 8192]).unwrap();
+        let path = path.to_str().unwrap();
+        assert!(matches!(
+            RedbStore::open(path),
+            Err(crate::HashmemError::Corrupted(_))
+        ));
+    }
+}
@@ -0,0 +1,146 @@
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// A single unit of modeled input.
+///
+/// Tokens are ordered by variant in declaration order, then by value, so
+/// characters sort before numbers, words and the markers. Tokens also hash,
+/// so they can key a `HashMap` or `BTreeMap` when aggregating counts.
+#[derive(
+    Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Encode, Decode,
+)]
+pub enum Token {
+    C(char),
+    Num(u64),
+    Word(String),
+    /// End of sequence; never rendered, generation stops when it is sampled.
+    Eos,
+    /// Start of sequence; never rendered, generation from an empty seed starts after it.
+    Bos,
+    /// A raw byte, in [`TokenMode::Byte`].
+    Byte(u8),
+}
+
+/// How input text is split into tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TokenMode {
+    /// Every character is a token.
+    #[default]
+    Char,
+    /// Every whitespace-delimited word is a token; output words are joined with spaces.
+    Word,
+    /// Every byte is a token, so arbitrary binary input can be modeled.
+    Byte,
+}
+
+/// Where the training path inserts `Token::Eos` markers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EosMode {
+    /// Never insert end-of-sequence markers.
+    #[default]
+    None,
+    /// Insert a marker after every line of the trained text.
+    Line,
+    /// Insert a marker after every trained text.
+    Document,
+}
+
+/// How text is normalized before it is tokenized, for training and prediction alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Normalization {
+    /// Keep the text as it is.
+    #[default]
+    None,
+    /// Lowercase every character, so 'A' and 'a' share statistics.
+    Lowercase,
+    /// Lowercase and drop accents and other combining marks, so 'É' becomes 'e'.
+    LowercaseStripAccents,
+}
+
+impl Normalization {
+    /// `src` normalized according to this mode.
+    pub fn apply<'a>(&self, src: &'a str) -> Cow<'a, str> {
+        match self {
+            Normalization::None => Cow::Borrowed(src),
+            Normalization::Lowercase => Cow::Owned(src.to_lowercase()),
+            Normalization::LowercaseStripAccents => {
+                let stripped: String = src.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect();
+                Cow::Owned(stripped.to_lowercase())
+            }
+        }
+    }
+}
+
+/// A continuation token together with how many times it was observed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Encode, Decode)]
+pub struct TokenEntry {
+    pub value: Token,
+    pub count: u64,
+}
+
+/// The number of observations in `entries`.
+///
+/// Counts saturate at `u64::MAX`, so their sum is taken in `u128`, where it
+/// cannot overflow.
+pub(crate) fn total_count(entries: &[TokenEntry]) -> u128 {
+    entries.iter().map(|e| e.count as u128).sum()
+}
+
+/// All continuations observed after a given context.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+pub struct TokenHits {
+    pub entries: Vec<TokenEntry>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalization_lowercases_and_strips_accents() {
+        let text = "Héllo WÖRLD, 한국";
+        assert_eq!(Normalization::None.apply(text), text);
+        assert_eq!(Normalization::Lowercase.apply(text), "héllo wörld, 한국");
+        assert_eq!(
+            Normalization::LowercaseStripAccents.apply(text),
+            "hello world, 한국"
+        );
+    }
+
+    #[test]
+    fn tokens_key_hash_sets() {
+        let tokens: std::collections::HashSet<Token> =
+            [Token::C('a'), Token::Num(1), Token::C('a'), Token::Eos]
+                .into_iter()
+                .collect();
+        assert_eq!(tokens.len(), 3);
+        assert!(tokens.contains(&Token::Num(1)));
+    }
+
+    #[test]
+    fn tokens_order_by_variant_then_value() {
+        let mut tokens = vec![
+            Token::Bos,
+            Token::Word("b".into()),
+            Token::Num(3),
+            Token::C('b'),
+            Token::Word("a".into()),
+            Token::C('a'),
+        ];
+        tokens.sort();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::C('a'),
+                Token::C('b'),
+                Token::Num(3),
+                Token::Word("a".into()),
+                Token::Word("b".into()),
+                Token::Bos,
+            ]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use crate::token::{Token, TokenMode};
+
+/// Splits text into tokens and joins generated tokens back into text.
+///
+/// Tokenizers see text after [normalization](crate::Normalization). A custom
+/// one is not recorded in the database or in snapshots, so a model must always
+/// be used with the tokenizer it was trained with.
+pub trait Tokenizer: Send + Sync {
+    /// The tokens of `src`, without sequence markers.
+    fn tokenize(&self, src: &str) -> Vec<Token>;
+
+    /// The text of `tokens`; `Token::Bos` and `Token::Eos` render as nothing.
+    fn detokenize(&self, tokens: &[Token]) -> String;
+
+    /// The text of `tokens` continuing text that already holds `previous` tokens.
+    ///
+    /// Generation renders its output this way, after the seed. The default is
+    /// [`detokenize`](Self::detokenize) of `tokens` alone; tokenizers that put
+    /// separators between tokens should also put one in front of a continuation.
+    fn detokenize_continuation(&self, tokens: &[Token], previous: usize) -> String {
+        let _ = previous;
+        self.detokenize(tokens)
+    }
+}
+
+/// Every character is a token, with runs of digits collapsed into numbers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CharTokenizer;
+
+/// Every whitespace-delimited word is a token; words are joined with spaces.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WordTokenizer;
+
+/// Every byte is a token; runs of bytes are decoded as UTF-8.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByteTokenizer;
+
+/// The built-in tokenizer of `mode`.
+pub(crate) fn for_mode(mode: TokenMode) -> Box<dyn Tokenizer> {
+    match mode {
+        TokenMode::Char => Box::new(CharTokenizer),
+        TokenMode::Word => Box::new(WordTokenizer),
+        TokenMode::Byte => Box::new(ByteTokenizer),
+    }
+}
+
+/// Split `src` into characters, collapsing each run of ASCII digits into a `Token::Num`.
+///
+/// Runs that overflow a `u64` or have a leading zero (and so would not render
+/// back to the same text) stay as individual character tokens.
+fn tokenize_chars(src: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut digits = String::new();
+    let flush_digits = |digits: &mut String, tokens: &mut Vec<Token>| {
+        if digits.is_empty() {
+            return;
+        }
+        match digits.parse::<u64>() {
+            Ok(n) if digits.len() == 1 || !digits.starts_with('0') => tokens.push(Token::Num(n)),
+            _ => tokens.extend(digits.chars().map(Token::C)),
+        }
+        digits.clear();
+    };
+    for c in src.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+        } else {
+            flush_digits(&mut digits, &mut tokens);
+            tokens.push(Token::C(c));
+        }
+    }
+    flush_digits(&mut digits, &mut tokens);
+    tokens
+}
+
+/// Whether `word` is punctuation that attaches to the word before it, like `,` or `)`.
+fn closes(word: &str) -> bool {
+    !word.is_empty()
+        && word
+            .chars()
+            .all(|c| matches!(c, '.' | ',' | ';' | ':' | '!' | '?' | ')' | ']' | '}' | '%'))
+}
+
+/// Whether `word` is punctuation that attaches to the word after it, like `(`.
+fn opens(word: &str) -> bool {
+    !word.is_empty() && word.chars().all(|c| matches!(c, '(' | '[' | '{'))
+}
+
+/// The text form of `tokens` of any kind, which follow `previous` tokens.
+///
+/// Words are separated by a space unless they start the text, except before
+/// closing punctuation and after opening brackets. Runs of bytes are decoded
+/// together as UTF-8, replacing invalid sequences.
+fn render(tokens: &[Token], previous: usize) -> String {
+    let mut out = String::new();
+    let mut bytes = vec![];
+    let mut after_opening = false;
+    for (i, t) in tokens.iter().enumerate() {
+        if let Token::Byte(b) = t {
+            bytes.push(*b);
+            continue;
+        }
+        out.push_str(&String::from_utf8_lossy(&bytes));
+        bytes.clear();
+        match t {
+            Token::C(c) => out.push(*c),
+            Token::Num(n) => out.push_str(&n.to_string()),
+            Token::Word(w) => {
+                if previous + i > 0 && !after_opening && !closes(w) {
+                    out.push(' ');
+                }
+                out.push_str(w);
+            }
+            Token::Byte(_) | Token::Eos | Token::Bos => {}
+        }
+        after_opening = matches!(t, Token::Word(w) if opens(w));
+    }
+    out.push_str(&String::from_utf8_lossy(&bytes));
+    out
+}
+
+impl Tokenizer for CharTokenizer {
+    fn tokenize(&self, src: &str) -> Vec<Token> {
+        tokenize_chars(src)
+    }
+
+    fn detokenize(&self, tokens: &[Token]) -> String {
+        render(tokens, 0)
+    }
+
+    fn detokenize_continuation(&self, tokens: &[Token], previous: usize) -> String {
+        render(tokens, previous)
+    }
+}
+
+impl Tokenizer for WordTokenizer {
+    fn tokenize(&self, src: &str) -> Vec<Token> {
+        src.split_whitespace()
+            .map(|w| Token::Word(w.to_string()))
+            .collect()
+    }
+
+    fn detokenize(&self, tokens: &[Token]) -> String {
+        render(tokens, 0)
+    }
+
+    fn detokenize_continuation(&self, tokens: &[Token], previous: usize) -> String {
+        render(tokens, previous)
+    }
+}
+
+impl Tokenizer for ByteTokenizer {
+    fn tokenize(&self, src: &str) -> Vec<Token> {
+        src.bytes().map(Token::Byte).collect()
+    }
+
+    fn detokenize(&self, tokens: &[Token]) -> String {
+        render(tokens, 0)
+    }
+
+    fn detokenize_continuation(&self, tokens: &[Token], previous: usize) -> String {
+        render(tokens, previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digit_runs_become_numbers() {
+        assert_eq!(
+            tokenize_chars("a12b"),
+            vec![Token::C('a'), Token::Num(12), Token::C('b')]
+        );
+        assert_eq!(tokenize_chars("07"), vec![Token::C('0'), Token::C('7')]);
+        let huge = "99999999999999999999";
+        assert_eq!(tokenize_chars(huge).len(), huge.len());
+    }
+
+    #[test]
+    fn char_and_byte_modes_round_trip_exactly() {
+        for text in [
+            "",
+            "hello, world",
+            "a12b 007 x",
+            "99999999999999999999",
+            "héllo ✓\n",
+        ] {
+            assert_eq!(
+                CharTokenizer.detokenize(&CharTokenizer.tokenize(text)),
+                text
+            );
+            assert_eq!(
+                ByteTokenizer.detokenize(&ByteTokenizer.tokenize(text)),
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn word_mode_round_trips_up_to_whitespace() {
+        let words = WordTokenizer;
+        assert_eq!(
+            words.detokenize(&words.tokenize("the cat  sat\non it.")),
+            "the cat sat on it."
+        );
+        assert_eq!(words.detokenize(&words.tokenize("  ")), "");
+        assert_eq!(
+            words.detokenize_continuation(&words.tokenize("ran"), 2),
+            " ran"
+        );
+    }
+
+    #[test]
+    fn word_mode_attaches_punctuation() {
+        let tokens: Vec<Token> = ["see", "(", "above", ")", ",", "then", "stop", "!"]
+            .iter()
+            .map(|w| Token::Word(w.to_string()))
+            .collect();
+        assert_eq!(WordTokenizer.detokenize(&tokens), "see (above), then stop!");
+        assert_eq!(WordTokenizer.detokenize_continuation(&tokens[4..5], 4), ",");
+    }
+}